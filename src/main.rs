@@ -1,19 +1,21 @@
 use std::borrow::Cow;
-use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
-use std::hash::Hasher;
+use std::future::IntoFuture;
 use std::net::{IpAddr, SocketAddr};
 use std::ops::Add;
+use std::path::PathBuf;
 use std::str::from_utf8;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use axum::extract::{Path, State};
-use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::{http, routing::get, Router};
 use clap::{crate_version, Parser};
-use deflate::deflate_bytes;
 use hyper::Request;
 use lazy_static::lazy_static;
 use maud::{html, Markup, PreEscaped, DOCTYPE};
@@ -29,7 +31,6 @@ use time::macros::{format_description, time};
 use time::{Date, OffsetDateTime, PrimitiveDateTime};
 use tower_http::trace::{MakeSpan, TraceLayer};
 use tracing::Span;
-use tracing_subscriber;
 use tracing_subscriber::layer::SubscriberExt;
 
 #[derive(RustEmbed)]
@@ -47,35 +48,426 @@ struct Cli {
 
     #[arg(long)]
     external_url_prefix: Option<String>,
+
+    #[arg(long, default_value_t = 10)]
+    index_page_size: usize,
+
+    #[arg(long)]
+    index_summaries: bool,
+
+    /// Cache-Control directives for a class of response (index, post, feed, asset, immutable, search, redirect or
+    /// error), given as class=directives, for example "feed=max-age=600, stale-while-revalidate=3600".
+    #[arg(long, value_parser = parse_cache_policy_arg)]
+    cache_policy: Vec<(CacheClass, String)>,
+
+    /// CDN-Cache-Control directives for a class of response, given as class=directives.
+    #[arg(long, value_parser = parse_cache_policy_arg)]
+    cdn_cache_policy: Vec<(CacheClass, String)>,
+
+    /// Cloudflare-CDN-Cache-Control directives for a class of response, given as class=directives.
+    #[arg(long, value_parser = parse_cache_policy_arg)]
+    cloudflare_cache_policy: Vec<(CacheClass, String)>,
+
+    /// A redirects file to use on top of the embedded one, in the same format.
+    #[arg(long)]
+    redirects: Option<PathBuf>,
+
+    /// The status of the redirects to the canonical form of a url: 301, 302, 307 or 308.
+    #[arg(long, default_value_t = 308, value_parser = parse_redirect_status)]
+    canonical_redirect_status: u16,
+
+    /// Redirect requests for any host other than the one of the external url prefix to it.
+    #[arg(long)]
+    canonical_host_redirect: bool,
+
+    /// The path to mount the site at, such as /blog. It is appended to the external url prefix unless that already
+    /// ends with it, and links, redirects, feeds and the sitemap all use it.
+    #[arg(long)]
+    base_path: Option<String>,
+
+    /// A PEM file with the certificate chain to terminate TLS with. The file is reloaded when it changes.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// A PEM file with the private key of the certificate. The file is reloaded when it changes.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// How often to check the certificate and key files for changes, in seconds.
+    #[arg(long, default_value_t = 60)]
+    tls_reload_interval: u64,
+
+    /// A port to listen on for plaintext http and redirect every request to https, when TLS is enabled.
+    #[arg(long, requires = "tls_cert")]
+    http_redirect_port: Option<u16>,
+
+    /// The max-age of the Strict-Transport-Security header sent when TLS is enabled, or 0 to leave it out.
+    #[arg(long, default_value_t = 31536000)]
+    hsts_max_age: u64,
+
+    /// How long to keep serving after SIGTERM or SIGINT with /readyz failing, so that load balancers stop sending new
+    /// requests before the listeners close, in seconds.
+    #[arg(long, default_value_t = 5)]
+    drain_period: u64,
+
+    /// How long to wait for in-flight requests once the listeners close before exiting anyway, in seconds.
+    #[arg(long, default_value_t = 20)]
+    shutdown_deadline: u64,
+}
+
+fn parse_redirect_status(arg: &str) -> Result<u16, String> {
+    match arg.parse::<u16>() {
+        Ok(status @ (301 | 302 | 307 | 308)) => Ok(status),
+        _ => Err("expected 301, 302, 307 or 308".to_string()),
+    }
+}
+
+fn parse_cache_policy_arg(arg: &str) -> Result<(CacheClass, String), String> {
+    let (name, directives) = arg
+        .split_once('=')
+        .ok_or("expected class=directives".to_string())?;
+    let class = CacheClass::ALL
+        .into_iter()
+        .find(|c| c.as_str().eq_ignore_ascii_case(name.trim()))
+        .ok_or(format!("unknown cache class {}", name))?;
+    let directives = directives.trim();
+    if directives.is_empty() || HeaderValue::from_str(directives).is_err() {
+        return Err(format!("invalid cache directives {:?}", directives));
+    }
+    Ok((class, directives.to_string()))
+}
+
+/// Options controlling how the site is rendered, separate from the cli so that tests can construct them directly.
+#[derive(Clone, Debug)]
+struct SiteOptions {
+    /// The number of posts on each page of the index, or 0 to put every post on the front page.
+    index_page_size: usize,
+    /// Whether to show the summary of each post on the index.
+    index_summaries: bool,
+    /// The caching headers of each class of response.
+    cache_policies: BTreeMap<CacheClass, CachePolicy>,
+    /// Redirects that take priority over the embedded redirects file.
+    redirects: Vec<Redirect>,
+    /// The status of the redirects to the canonical form of a url, adding the trailing slash, normalising the path and
+    /// moving to the canonical host.
+    canonical_redirect_status: StatusCode,
+    /// Whether to redirect requests for any host other than the one of the external url prefix to it.
+    canonical_host_redirect: bool,
+    /// The Strict-Transport-Security header to send on every response, set when the server terminates TLS.
+    strict_transport_security: Option<String>,
+}
+
+impl Default for SiteOptions {
+    fn default() -> Self {
+        SiteOptions {
+            index_page_size: 10,
+            index_summaries: false,
+            cache_policies: CacheClass::ALL
+                .iter()
+                .map(|class| match class {
+                    CacheClass::Immutable => (*class, CachePolicy::new(IMMUTABLE_CACHE_CONTROL)),
+                    _ => (*class, CachePolicy::new(CACHE_CONTROL)),
+                })
+                .collect(),
+            redirects: vec![],
+            canonical_redirect_status: StatusCode::PERMANENT_REDIRECT,
+            canonical_host_redirect: false,
+            strict_transport_security: None,
+        }
+    }
+}
+
+/// The classes of response that can be given their own cache policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum CacheClass {
+    /// The index, its pages and the archives.
+    Index,
+    Post,
+    /// Feeds, sitemaps and the other machine readable files.
+    Feed,
+    /// Images and other files served under their plain names.
+    Asset,
+    /// Assets served under their fingerprinted names, which never change.
+    Immutable,
+    Search,
+    Redirect,
+    Error,
+}
+
+impl CacheClass {
+    const ALL: [CacheClass; 8] = [
+        CacheClass::Index,
+        CacheClass::Post,
+        CacheClass::Feed,
+        CacheClass::Asset,
+        CacheClass::Immutable,
+        CacheClass::Search,
+        CacheClass::Redirect,
+        CacheClass::Error,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            CacheClass::Index => "index",
+            CacheClass::Post => "post",
+            CacheClass::Feed => "feed",
+            CacheClass::Asset => "asset",
+            CacheClass::Immutable => "immutable",
+            CacheClass::Search => "search",
+            CacheClass::Redirect => "redirect",
+            CacheClass::Error => "error",
+        }
+    }
+}
+
+/// The caching headers sent with a class of response. CDN-Cache-Control is honoured by CDNs in place of Cache-Control
+/// and Cloudflare-CDN-Cache-Control by Cloudflare alone, so the layer in front can cache for longer than browsers do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CachePolicy {
+    cache_control: String,
+    cdn_cache_control: Option<String>,
+    cloudflare_cdn_cache_control: Option<String>,
+}
+
+impl CachePolicy {
+    fn new(cache_control: &str) -> Self {
+        CachePolicy {
+            cache_control: cache_control.to_string(),
+            cdn_cache_control: None,
+            cloudflare_cdn_cache_control: None,
+        }
+    }
+}
+
+/// A rule of the redirect map, sending requests for a path that doesn't exist (anymore) to where the content lives now,
+/// or saying that it is gone for good when there is no target.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Redirect {
+    /// The old path, which also matches everything under it when it ends in /*.
+    from: String,
+    /// The new path or url, with any * replaced by what the * of the old path matched.
+    to: Option<String>,
+    status: StatusCode,
 }
 
 #[derive(Clone, Debug)]
 struct Item {
     content: Cow<'static, [u8]>,
-    compressed: Cow<'static, [u8]>,
+    /// The compressed variants, shared with any other item serving the same content such as a fingerprinted asset.
+    encoded: Vec<(ContentEncoding, Bytes)>,
     content_type: HeaderValue,
     etag: String,
+    last_modified: Option<PrimitiveDateTime>,
+    script_hashes: Vec<String>,
+    image_variants: Vec<Item>,
+    cache_class: CacheClass,
     children: HashMap<String, Cow<'static, Item>>,
 }
 
+/// The content codings that items are precomputed in, ordered by preference when the client accepts several equally.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ContentEncoding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Identity,
+}
+
+impl ContentEncoding {
+    const PREFERENCE: [ContentEncoding; 4] = [
+        ContentEncoding::Brotli,
+        ContentEncoding::Zstd,
+        ContentEncoding::Gzip,
+        ContentEncoding::Identity,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Zstd => "zstd",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Identity => "identity",
+        }
+    }
+
+    fn matches(&self, coding: &str) -> bool {
+        coding.eq_ignore_ascii_case(self.as_str())
+            || (*self == ContentEncoding::Gzip && coding.eq_ignore_ascii_case("x-gzip"))
+    }
+}
+
 struct Post {
     path: String,
     title: String,
     date: PrimitiveDateTime,
+    description: String,
+    image: Option<String>,
+    canonical: Option<String>,
+    tags: Vec<String>,
+    related: Vec<String>,
+    aliases: Vec<String>,
+    featured: bool,
+    links: Vec<String>,
+    backlinks: Vec<String>,
+    word_count: usize,
+    json_ld: String,
+    html: String,
+    text: String,
     pre_rendered: Cow<'static, [u8]>,
     assets: HashMap<String, Cow<'static, [u8]>>,
 }
 
 struct SharedState {
     root: Cow<'static, Item>,
+    /// The path the site is mounted at, prefixed to every root-relative link and redirect.
+    base_path: String,
+    redirects: Vec<Redirect>,
+    canonical_redirect_status: StatusCode,
+    /// The external url prefix when requests for other hosts are redirected to it.
+    canonical_prefix: Option<String>,
+    cache_policies: BTreeMap<CacheClass, CachePolicy>,
+    strict_transport_security: Option<HeaderValue>,
+    /// Set once a shutdown has started, failing the readiness check while requests drain.
+    draining: Arc<AtomicBool>,
+    watchdog: Watchdog,
+    post_count: usize,
+    /// The bytes of content held by the item tree, counting every precomputed encoding and image variant.
+    state_bytes: usize,
+    metrics: Mutex<Metrics>,
     not_found: Cow<'static, Item>,
+    gone: Cow<'static, Item>,
+    search_index: SearchIndex,
+    head: Markup,
+    footer: Markup,
+}
+
+/// Records when a task on the runtime last got to run, so that a stalled runtime fails the liveness check even when the
+/// health check itself still gets scheduled. The watchdog task and the health check share the multi-threaded runtime,
+/// so this only catches stalls of the whole runtime, such as every worker being blocked or the timers not being
+/// driven. A single blocked worker goes unnoticed since the other workers keep running both.
+struct Watchdog {
+    /// Milliseconds since START_TIME of the last check in, or u64::MAX before the watchdog task has started.
+    last_beat: AtomicU64,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog {
+            last_beat: AtomicU64::new(u64::MAX),
+        }
+    }
+}
+
+impl Watchdog {
+    fn beat(&self) {
+        self.last_beat
+            .store(START_TIME.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Whether the watchdog has gone at least the threshold without checking in. There is nothing to judge the
+    /// runtime by until the watchdog task has started.
+    fn is_stalled(&self, threshold: Duration) -> bool {
+        match self.last_beat.load(Ordering::Relaxed) {
+            u64::MAX => false,
+            last_beat => {
+                START_TIME
+                    .elapsed()
+                    .saturating_sub(Duration::from_millis(last_beat))
+                    >= threshold
+            }
+        }
+    }
+}
+
+/// Request metrics exposed at /metricz in the Prometheus text format.
+#[derive(Default)]
+struct Metrics {
+    /// Requests by route and status.
+    requests: BTreeMap<(String, u16), u64>,
+    /// Request latency by route.
+    durations: BTreeMap<String, Histogram>,
+    /// Body bytes sent by content encoding.
+    bytes: BTreeMap<&'static str, u64>,
+    /// Requests carrying If-None-Match or If-Modified-Since, and how many of them were answered with a 304.
+    conditional_requests: u64,
+    not_modified: u64,
+    /// Not found responses by the class of path requested.
+    not_found: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// The count of observations in each bucket of LATENCY_BUCKETS, not cumulative.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|b| value <= *b) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Check in with the watchdog on an interval for as long as the runtime keeps scheduling this task on any of its
+/// workers.
+async fn run_watchdog(state: Arc<SharedState>) {
+    let mut ticker = tokio::time::interval(WATCHDOG_INTERVAL);
+    loop {
+        ticker.tick().await;
+        state.watchdog.beat();
+    }
+}
+
+impl SharedState {
+    fn cache_policy(&self, class: CacheClass) -> &CachePolicy {
+        &self.cache_policies[&class]
+    }
+}
+
+struct SearchDocument {
+    path: String,
+    title: String,
+    date: PrimitiveDateTime,
+    text: String,
+}
+
+/// An inverted index from lowercase terms to the documents containing them and the weighted frequency of the term
+/// within each document.
+struct SearchIndex {
+    documents: Vec<SearchDocument>,
+    terms: HashMap<String, Vec<(usize, u32)>>,
 }
 
 const CONTENT_FILE_NAME: &str = "content.md";
+const REDIRECTS_FILE_NAME: &str = "redirects.txt";
 const HTML_CONTENT_TYPE: &str = "text/html; charset=utf-8";
 const PLAIN_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+const ATOM_CONTENT_TYPE: &str = "application/atom+xml";
 const CRATE_VERSION: &str = crate_version!();
 const CACHE_CONTROL: &str = "max-age=300";
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+const ASSET_FINGERPRINT_LENGTH: usize = 8;
+// in order of preference, these being smaller than the images they are alternatives to
+const IMAGE_VARIANT_EXTENSIONS: [&str; 2] = ["avif", "webp"];
+const COMPRESSION_MIN_SAVING_PERCENT: usize = 10;
+const RANGE_MAX_COUNT: usize = 16;
+const SITEMAP_MAX_URLS: usize = 50000;
+const DESCRIPTION_MAX_LENGTH: usize = 200;
+const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'none'; style-src 'nonce-123456789'; img-src 'self' data: https:";
+const SEARCH_TITLE_WEIGHT: u32 = 5;
+const RELATED_POSTS_COUNT: usize = 3;
+const SEARCH_MAX_RESULTS: usize = 20;
+const SEARCH_SNIPPET_LENGTH: usize = 240;
+const SOCIAL_CARD_FILE_NAME: &str = "og.png";
+const SOCIAL_CARD_WIDTH: u32 = 1200;
+const SOCIAL_CARD_HEIGHT: u32 = 630;
+const SOCIAL_CARD_FONT: &str = "fonts/DejaVuSans-Bold.ttf";
 
 const POST_DATE_FORMAT: &[FormatItem] =
     format_description!("[day padding:none] [month repr:long] [year]");
@@ -84,27 +476,45 @@ const RFC3339_DATE_FORMAT: &[FormatItem] =
 const RFC2822_DATE_FORMAT: &[FormatItem] = format_description!(
     "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
 );
-const FOOTER_DATE_FORMAT: &[FormatItem] = RFC3339_DATE_FORMAT;
+// the IMF-fixdate of RFC 9110 is the same as the date format used by rss
+const HTTP_DATE_FORMAT: &[FormatItem] = RFC2822_DATE_FORMAT;
+const ASCTIME_DATE_FORMAT: &[FormatItem] = format_description!(
+    "[weekday repr:short] [month repr:short] [day padding:space] [hour]:[minute]:[second] [year]"
+);
+const RFC850_DATE_FORMAT: &[FormatItem] = format_description!(
+    "[weekday repr:long], [day]-[month repr:short]-[year repr:last_two] [hour]:[minute]:[second] GMT"
+);
 const ENCODED_FAVICON: &str = "data:image/svg+xml,%3Csvg version='1.0' xmlns='http://www.w3.org/2000/svg' xmlns:xlink='http://www.w3.org/1999/xlink' viewBox='0 0 64 64' enable-background='new 0 0 64 64' xml:space='preserve'%3E%3Cg%3E%3Cg%3E%3Cpolygon fill='%23F9EBB2' points='46,3.414 46,14 56.586,14 '/%3E%3Cpath fill='%23F9EBB2' d='M45,16c-0.553,0-1-0.447-1-1V2H8C6.896,2,6,2.896,6,4v56c0,1.104,0.896,2,2,2h48c1.104,0,2-0.896,2-2V16 H45z'/%3E%3C/g%3E%3Cpath fill='%23394240' d='M14,26c0,0.553,0.447,1,1,1h34c0.553,0,1-0.447,1-1s-0.447-1-1-1H15C14.447,25,14,25.447,14,26z'/%3E%3Cpath fill='%23394240' d='M49,37H15c-0.553,0-1,0.447-1,1s0.447,1,1,1h34c0.553,0,1-0.447,1-1S49.553,37,49,37z'/%3E%3Cpath fill='%23394240' d='M49,43H15c-0.553,0-1,0.447-1,1s0.447,1,1,1h34c0.553,0,1-0.447,1-1S49.553,43,49,43z'/%3E%3Cpath fill='%23394240' d='M49,49H15c-0.553,0-1,0.447-1,1s0.447,1,1,1h34c0.553,0,1-0.447,1-1S49.553,49,49,49z'/%3E%3Cpath fill='%23394240' d='M49,31H15c-0.553,0-1,0.447-1,1s0.447,1,1,1h34c0.553,0,1-0.447,1-1S49.553,31,49,31z'/%3E%3Cpath fill='%23394240' d='M15,20h16c0.553,0,1-0.447,1-1s-0.447-1-1-1H15c-0.553,0-1,0.447-1,1S14.447,20,15,20z'/%3E%3Cpath fill='%23394240' d='M59.706,14.292L45.708,0.294C45.527,0.112,45.277,0,45,0H8C5.789,0,4,1.789,4,4v56c0,2.211,1.789,4,4,4h48 c2.211,0,4-1.789,4-4V15C60,14.723,59.888,14.473,59.706,14.292z M46,3.414L56.586,14H46V3.414z M58,60c0,1.104-0.896,2-2,2H8 c-1.104,0-2-0.896-2-2V4c0-1.104,0.896-2,2-2h36v13c0,0.553,0.447,1,1,1h13V60z'/%3E%3Cpolygon opacity='0.15' fill='%23231F20' points='46,3.414 56.586,14 46,14 '/%3E%3C/g%3E%3C/svg%3E";
 
 lazy_static! {
     static ref START_TIME: std::time::Instant = std::time::Instant::now();
+    /// The year of the newest embedded post, which post directories start with, for the copyright notice. Taking it
+    /// from the content rather than the clock keeps the pre-rendered pages the same until the content changes.
+    static ref COPYRIGHT_YEAR: i32 = Asset::iter()
+        .filter(|x| x.ends_with(CONTENT_FILE_NAME))
+        .filter_map(|x| x.split('/').rev().nth(1)?.get(..4)?.parse().ok())
+        .max()
+        .unwrap_or_default();
 }
 
-fn collect_posts(external_url_prefix: &String) -> Vec<Post> {
-    let mut options = pulldown_cmark::Options::empty();
-    options.insert(pulldown_cmark::Options::ENABLE_STRIKETHROUGH);
-    options.insert(pulldown_cmark::Options::ENABLE_TABLES);
-    options.insert(pulldown_cmark::Options::ENABLE_FOOTNOTES);
+/// The upper bounds of the request latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
 
-    let title_re = regex::Regex::new(r#"<meta x-title="(.+)"/?>"#).unwrap();
+/// How often the watchdog task checks in with the runtime.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the watchdog may go without checking in before the runtime is considered stalled.
+const WATCHDOG_STALL_THRESHOLD: Duration = Duration::from_secs(10);
 
-    Asset::iter()
+fn collect_posts(external_url_prefix: &String) -> Vec<Post> {
+    let mut posts = Asset::iter()
         .filter(|x| x.ends_with(CONTENT_FILE_NAME))
         .map(|x| {
             let path = x
                 .split("/")
-                .take_while(|y| !(*y == CONTENT_FILE_NAME))
+                .take_while(|y| *y != CONTENT_FILE_NAME)
                 .last()
                 .unwrap()
                 .to_string();
@@ -112,33 +522,6 @@ fn collect_posts(external_url_prefix: &String) -> Vec<Post> {
             let raw_bytes = Asset::get(&x).unwrap();
             let raw_content = from_utf8(raw_bytes.data.as_ref()).unwrap();
 
-            let parsed_title = title_re
-                .captures(raw_content)
-                .map(|c| c.get(1).unwrap().as_str().to_owned())
-                .unwrap_or("unknown".to_string());
-
-            let format = format_description!("[year][month][day]");
-            let parsed_date = path
-                .split("-")
-                .take(1)
-                .last()
-                .map(|c| Date::parse(c, &format).unwrap())
-                .unwrap_or(OffsetDateTime::now_utc().date());
-            let parsed_date_time = PrimitiveDateTime::new(parsed_date, time!(0:00));
-
-            let parser = pulldown_cmark::Parser::new_ext(raw_content, options);
-            let mut html_output = String::new();
-            pulldown_cmark::html::push_html(&mut html_output, parser);
-            let tree: Markup = PreEscaped { 0: html_output };
-
-            let content = pre_render_post(
-                &parsed_title,
-                &parsed_date_time,
-                &tree,
-                &external_url_prefix,
-                &path,
-            );
-
             let mut assets = HashMap::new();
 
             let prefix = x
@@ -159,36 +542,533 @@ fn collect_posts(external_url_prefix: &String) -> Vec<Post> {
                     );
                 });
 
-            Post {
-                path,
-                title: parsed_title,
-                date: parsed_date_time,
-                pre_rendered: content,
-                assets,
+            parse_post(path, raw_content, assets, external_url_prefix)
+        })
+        .collect::<Vec<Post>>();
+
+    compute_related_posts(&mut posts);
+    compute_backlinks(&mut posts);
+    let rendered: Vec<Cow<'static, [u8]>> = posts
+        .iter()
+        .map(|x| pre_render_post(x, &posts, external_url_prefix))
+        .collect();
+    for (x, r) in posts.iter_mut().zip(rendered) {
+        x.pre_rendered = r;
+    }
+    posts
+}
+
+/// Parse the markdown source of a post and the metadata it declares. The related posts, backlinks and pre-rendered
+/// page need every other post, so they are filled in by collect_posts.
+fn parse_post(
+    path: String,
+    raw_content: &str,
+    assets: HashMap<String, Cow<'static, [u8]>>,
+    external_url_prefix: &String,
+) -> Post {
+    let mut options = pulldown_cmark::Options::empty();
+    options.insert(pulldown_cmark::Options::ENABLE_STRIKETHROUGH);
+    options.insert(pulldown_cmark::Options::ENABLE_TABLES);
+    options.insert(pulldown_cmark::Options::ENABLE_FOOTNOTES);
+
+    // posts declare metadata as <meta x-key="value"/> tags: title, description, image, canonical (when the post was
+    // originally published elsewhere), tags (comma separated), related (comma separated post paths), aliases (comma
+    // separated old paths that redirect to the post), and featured ("true" to pin the post to the top of the front
+    // page).
+    let meta_re = regex::Regex::new(r#"<meta x-([a-z-]+)="(.+?)"/?>"#).unwrap();
+    let image_re = regex::Regex::new(r#"<img src="([^"]+)""#).unwrap();
+
+    let meta: HashMap<String, String> = meta_re
+        .captures_iter(raw_content)
+        .map(|c| (c[1].to_string(), c[2].to_string()))
+        .collect();
+    let parsed_title = meta.get("title").cloned().unwrap_or("unknown".to_string());
+
+    let format = format_description!("[year][month][day]");
+    let parsed_date = path
+        .split("-")
+        .take(1)
+        .last()
+        .map(|c| Date::parse(c, &format).unwrap())
+        .unwrap_or(OffsetDateTime::now_utc().date());
+    let parsed_date_time = PrimitiveDateTime::new(parsed_date, time!(0:00));
+
+    let fingerprints = asset_fingerprints(&assets);
+
+    let parser = pulldown_cmark::Parser::new_ext(raw_content, options)
+        .map(|event| fingerprint_asset_links(event, &fingerprints))
+        .map(|event| prefix_root_links(event, url_base_path(external_url_prefix)));
+    let mut html_output = String::new();
+    pulldown_cmark::html::push_html(&mut html_output, parser);
+    let tree: Markup = PreEscaped(html_output);
+    let (text, first_paragraph) = markdown_plain_text(raw_content, options);
+    let links = extract_internal_links(raw_content, options, external_url_prefix, &path);
+
+    // the social image is either declared in the metadata or the first image embedded in the post
+    let meta_image = meta.get("image").map(|i| match i.starts_with('/') {
+        true => format!("{}{}", url_base_path(external_url_prefix), i),
+        false => i.clone(),
+    });
+    let image = meta_image
+        .as_deref()
+        .or(image_re
+            .captures(&tree.0)
+            .map(|c| c.get(1).unwrap().as_str()))
+        .map(|i| absolutize_url(i, external_url_prefix, &path));
+
+    let mut post = Post {
+        path,
+        title: parsed_title,
+        date: parsed_date_time,
+        description: meta
+            .get("description")
+            .cloned()
+            .unwrap_or(truncate_words(&first_paragraph, DESCRIPTION_MAX_LENGTH)),
+        image,
+        canonical: meta.get("canonical").cloned(),
+        tags: meta.get("tags").map(|t| split_list(t)).unwrap_or_default(),
+        related: meta
+            .get("related")
+            .map(|t| split_list(t))
+            .unwrap_or_default(),
+        aliases: meta
+            .get("aliases")
+            .map(|t| split_list(t))
+            .unwrap_or_default(),
+        featured: meta
+            .get("featured")
+            .map(|f| f.eq("true"))
+            .unwrap_or_default(),
+        links,
+        backlinks: vec![],
+        word_count: text.split_whitespace().count(),
+        json_ld: String::new(),
+        html: tree.into_string(),
+        text,
+        pre_rendered: Cow::from(vec![]),
+        assets,
+    };
+    post.json_ld = render_post_json_ld(&post, external_url_prefix);
+    post
+}
+
+/// The name an asset is also served under with a fingerprint of its content before the extension, so that the url
+/// changes whenever the content does and responses can be cached forever.
+fn fingerprint_asset_name(name: &str, contents: &[&[u8]]) -> String {
+    use sha2::Digest;
+    let mut digest = sha2::Sha256::new();
+    contents.iter().for_each(|c| digest.update(c));
+    let fingerprint: String = digest.finalize()[..ASSET_FINGERPRINT_LENGTH / 2]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    match name.rsplit_once('.') {
+        Some((stem, extension)) => format!("{}.{}.{}", stem, fingerprint, extension),
+        None => format!("{}.{}", name, fingerprint),
+    }
+}
+
+/// The fingerprinted names of the assets of a post. Image variants are served at the url of their image, so their
+/// bytes are part of its fingerprint too.
+fn asset_fingerprints(assets: &HashMap<String, Cow<'static, [u8]>>) -> HashMap<String, String> {
+    assets
+        .iter()
+        .map(|(name, data)| {
+            let mut contents = vec![data.as_ref()];
+            contents.extend(
+                IMAGE_VARIANT_EXTENSIONS
+                    .iter()
+                    .filter_map(|extension| assets.get(&format!("{}.{}", name, extension)))
+                    .map(|variant| variant.as_ref()),
+            );
+            (name.clone(), fingerprint_asset_name(name, &contents))
+        })
+        .collect()
+}
+
+/// Point relative links and images to the assets of a post at their fingerprinted names.
+fn fingerprint_asset_links<'a>(
+    event: pulldown_cmark::Event<'a>,
+    fingerprints: &HashMap<String, String>,
+) -> pulldown_cmark::Event<'a> {
+    use pulldown_cmark::{Event, Tag};
+    let fingerprinted = |dest_url: &str| {
+        fingerprints
+            .get(dest_url.strip_prefix("./").unwrap_or(dest_url))
+            .map(|f| f.clone().into())
+    };
+    match event {
+        Event::Start(Tag::Image {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Image {
+            link_type,
+            dest_url: fingerprinted(&dest_url).unwrap_or(dest_url),
+            title,
+            id,
+        }),
+        Event::Start(Tag::Link {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Link {
+            link_type,
+            dest_url: fingerprinted(&dest_url).unwrap_or(dest_url),
+            title,
+            id,
+        }),
+        _ => event,
+    }
+}
+
+/// Mount the root-relative links and images of a post under the base path of the site.
+fn prefix_root_links<'a>(
+    event: pulldown_cmark::Event<'a>,
+    base_path: &str,
+) -> pulldown_cmark::Event<'a> {
+    use pulldown_cmark::{CowStr, Event, Tag};
+    let prefixed = |dest_url: CowStr<'a>| -> CowStr<'a> {
+        if !base_path.is_empty() && dest_url.starts_with('/') && !dest_url.starts_with("//") {
+            format!("{}{}", base_path, dest_url).into()
+        } else {
+            dest_url
+        }
+    };
+    match event {
+        Event::Start(Tag::Image {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Image {
+            link_type,
+            dest_url: prefixed(dest_url),
+            title,
+            id,
+        }),
+        Event::Start(Tag::Link {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Link {
+            link_type,
+            dest_url: prefixed(dest_url),
+            title,
+            id,
+        }),
+        _ => event,
+    }
+}
+
+/// Find the paths of the other posts that the markdown content links to, whether by relative link, absolute path, or
+/// full url using the external url prefix.
+fn extract_internal_links(
+    raw_content: &str,
+    options: pulldown_cmark::Options,
+    external_url_prefix: &str,
+    path: &str,
+) -> Vec<String> {
+    use pulldown_cmark::{Event, Tag};
+    let mut links: Vec<String> = vec![];
+    for event in pulldown_cmark::Parser::new_ext(raw_content, options) {
+        if let Event::Start(Tag::Link { dest_url, .. }) = event {
+            let mut url = dest_url.as_ref();
+            if !external_url_prefix.is_empty() {
+                url = url.strip_prefix(external_url_prefix).unwrap_or(url);
+            }
+            let resolved = absolutize_url(url, "", path);
+            if let Some(target) = resolved
+                .strip_prefix('/')
+                .and_then(|r| r.split(['/', '?', '#']).next())
+            {
+                if !target.is_empty() && target != path && !links.iter().any(|l| l == target) {
+                    links.push(target.to_string());
+                }
+            }
+        }
+    }
+    links
+}
+
+/// Build the reverse link graph so that each post knows which other posts link to it, newest first. Links to paths
+/// which are not posts are reported since they are likely broken.
+fn compute_backlinks(posts: &mut [Post]) {
+    let mut backlinks: HashMap<String, Vec<(PrimitiveDateTime, String)>> = HashMap::new();
+    for x in posts.iter() {
+        for l in x.links.iter() {
+            if posts.iter().any(|y| y.path.eq(l)) {
+                backlinks
+                    .entry(l.clone())
+                    .or_default()
+                    .push((x.date, x.path.clone()));
+            } else {
+                tracing::warn!("post {} links to unknown post {}", x.path, l);
+            }
+        }
+    }
+    for x in posts.iter_mut() {
+        if let Some(mut b) = backlinks.remove(&x.path) {
+            b.sort_by(|a, b| b.cmp(a));
+            x.backlinks = b.into_iter().map(|(_, p)| p).collect();
+        }
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|y| y.trim().to_string())
+        .filter(|y| !y.is_empty())
+        .collect()
+}
+
+/// Count the terms in a post, with terms in the title weighted higher than those in the body.
+fn term_counts(title: &str, text: &str) -> HashMap<String, u32> {
+    let mut counts: HashMap<String, u32> = HashMap::new();
+    for t in tokenize(title) {
+        *counts.entry(t).or_default() += SEARCH_TITLE_WEIGHT;
+    }
+    for t in tokenize(text) {
+        *counts.entry(t).or_default() += 1;
+    }
+    counts
+}
+
+/// Fill in the related posts of every post which doesn't list its own, using the cosine similarity of the tf-idf
+/// vectors of the posts. Ordered maps and the path tie-break keep the result stable across restarts.
+fn compute_related_posts(posts: &mut [Post]) {
+    let counts: Vec<HashMap<String, u32>> = posts
+        .iter()
+        .map(|x| term_counts(&x.title, &x.text))
+        .collect();
+    let mut document_frequency: HashMap<&String, usize> = HashMap::new();
+    for c in counts.iter() {
+        for t in c.keys() {
+            *document_frequency.entry(t).or_default() += 1;
+        }
+    }
+    let n = posts.len() as f64;
+    let vectors: Vec<BTreeMap<&String, f64>> = counts
+        .iter()
+        .map(|c| {
+            let mut v: BTreeMap<&String, f64> = c
+                .iter()
+                .map(|(t, tf)| {
+                    let idf = (n / *document_frequency.get(t).unwrap() as f64).ln();
+                    (t, (1.0 + (*tf as f64).ln()) * idf)
+                })
+                .filter(|(_, w)| *w > 0.0)
+                .collect();
+            let norm = v.values().map(|w| w * w).sum::<f64>().sqrt();
+            if norm > 0.0 {
+                v.values_mut().for_each(|w| *w /= norm);
             }
+            v
         })
-        .collect::<Vec<Post>>()
+        .collect();
+
+    let paths: Vec<String> = posts.iter().map(|x| x.path.clone()).collect();
+    for (i, x) in posts.iter_mut().enumerate() {
+        if !x.related.is_empty() {
+            x.related.retain(|r| {
+                let known = paths.contains(r);
+                if !known {
+                    tracing::warn!("post {} lists unknown related post {}", x.path, r);
+                }
+                known
+            });
+            continue;
+        }
+        let mut scores: Vec<(f64, &String)> = vectors
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(j, v)| {
+                let score = vectors[i]
+                    .iter()
+                    .filter_map(|(t, w)| v.get(t).map(|w2| w * w2))
+                    .sum::<f64>();
+                (score, &paths[j])
+            })
+            .collect();
+        scores.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(b.1)));
+        x.related = scores
+            .into_iter()
+            .take(RELATED_POSTS_COUNT)
+            .map(|(_, p)| p.clone())
+            .collect();
+    }
 }
 
-fn build_shared_state(mut posts: Vec<Post>, external_url_prefix: &String) -> SharedState {
+/// Extract the plain text of the markdown content without any markup, along with the plain text of the first
+/// paragraph which is used as a summary when the post doesn't declare one.
+fn markdown_plain_text(raw_content: &str, options: pulldown_cmark::Options) -> (String, String) {
+    use pulldown_cmark::{Event, TagEnd};
+    let mut text = String::new();
+    let mut first_paragraph: Option<String> = None;
+    for event in pulldown_cmark::Parser::new_ext(raw_content, options) {
+        match event {
+            Event::Text(t) | Event::Code(t) => text.push_str(t.as_ref()),
+            Event::SoftBreak | Event::HardBreak => text.push(' '),
+            Event::End(TagEnd::Paragraph) => {
+                if first_paragraph.is_none() {
+                    first_paragraph = Some(text.trim().to_string());
+                }
+                text.push('\n');
+            }
+            Event::End(_) => text.push('\n'),
+            _ => {}
+        }
+    }
+    let first_paragraph = first_paragraph.unwrap_or_default();
+    (text, first_paragraph)
+}
+
+/// Truncate the text to at most max_length bytes on a word boundary, including the ellipsis added when anything was
+/// removed. A first word that is too long on its own is cut short instead.
+fn truncate_words(text: &str, max_length: usize) -> String {
+    let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if text.len() <= max_length {
+        return text;
+    }
+    let max_length = max_length.saturating_sub('…'.len_utf8());
+    let mut out = String::new();
+    for word in text.split(' ') {
+        let separator = if out.is_empty() { 0 } else { 1 };
+        if out.len() + separator + word.len() > max_length {
+            break;
+        }
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        out.push_str(word);
+    }
+    if out.is_empty() {
+        out = text
+            .chars()
+            .scan(0, |len, c| {
+                *len += c.len_utf8();
+                Some((*len, c))
+            })
+            .take_while(|(len, _)| *len <= max_length)
+            .map(|(_, c)| c)
+            .collect();
+    }
+    out.push('…');
+    out
+}
+
+fn build_shared_state(
+    mut posts: Vec<Post>,
+    external_url_prefix: &String,
+    options: &SiteOptions,
+) -> SharedState {
+    let base_path = url_base_path(external_url_prefix);
     posts.reverse();
     tracing::info!("Building shared state from {} posts", posts.len());
 
-    let root_content = pre_render_index(&posts, external_url_prefix);
+    let root_content = pre_render_index(&posts, 1, options, external_url_prefix);
     let mut root: Cow<'static, Item> = Cow::Owned(Item {
         content: root_content.clone(),
-        compressed: Cow::from(deflate_bytes(root_content.as_ref())),
+        encoded: encode_variants("/", HTML_CONTENT_TYPE, root_content.as_ref()),
         content_type: HeaderValue::from_str(HTML_CONTENT_TYPE).unwrap(),
-        etag: make_hash("", "").to_string(),
+        etag: make_etag(root_content.as_ref()),
+        last_modified: posts.iter().map(|x| x.date).max(),
+        script_hashes: vec![make_csp_hash(&render_index_json_ld(
+            &posts,
+            external_url_prefix,
+        ))],
+        image_variants: vec![],
+        cache_class: CacheClass::Index,
         children: HashMap::new(),
     });
 
+    let dated_posts = dated_index_posts(&posts);
+    let page_count = index_page_count(dated_posts.len(), options);
+    if page_count > 1 {
+        let content = pre_render_archive(
+            "All pages",
+            "/page/",
+            html! {
+                ul.index-nav-ul {
+                    @for page in 1..=page_count {
+                        li {
+                            p {
+                                a href={ (base_path) (index_page_path(page)) } { "Page " (page) }
+                            }
+                        }
+                    }
+                }
+            },
+            external_url_prefix,
+        );
+        let mut pages_item: Cow<'static, Item> = Cow::Owned(Item {
+            content: content.clone(),
+            encoded: encode_variants("/page/", HTML_CONTENT_TYPE, content.as_ref()),
+            content_type: HeaderValue::from_str(HTML_CONTENT_TYPE).unwrap(),
+            etag: make_etag(content.as_ref()),
+            last_modified: None,
+            script_hashes: vec![],
+            image_variants: vec![],
+            cache_class: CacheClass::Index,
+            children: HashMap::new(),
+        });
+        for page in 2..=page_count {
+            let content = pre_render_index(&posts, page, options, external_url_prefix);
+            let page_item = Cow::Owned(Item {
+                content: content.clone(),
+                encoded: encode_variants(
+                    &index_page_path(page),
+                    HTML_CONTENT_TYPE,
+                    content.as_ref(),
+                ),
+                content_type: HeaderValue::from_str(HTML_CONTENT_TYPE).unwrap(),
+                etag: make_etag(content.as_ref()),
+                last_modified: dated_posts
+                    .iter()
+                    .skip((page - 1) * options.index_page_size)
+                    .map(|x| x.date)
+                    .next(),
+                script_hashes: vec![],
+                image_variants: vec![],
+                cache_class: CacheClass::Index,
+                children: HashMap::new(),
+            });
+            pages_item
+                .to_mut()
+                .children
+                .insert(page.to_string(), page_item);
+        }
+        root.to_mut()
+            .children
+            .insert("page".to_string(), pages_item);
+    }
+
     let url_image_data = Asset::get("url-image.jpg").unwrap().data;
+    let url_image_webp_data = Asset::get("url-image.jpg.webp").unwrap().data;
     let url_image_item = Cow::Owned(Item {
         content: url_image_data.clone(),
-        compressed: Cow::from(deflate_bytes(url_image_data.as_ref())),
+        encoded: encode_variants("/url-image.jpg", "image/jpeg", url_image_data.as_ref()),
         content_type: HeaderValue::from_str("image/jpeg").unwrap(),
-        etag: make_hash("url-image.jpg", "").to_string(),
+        etag: make_etag(url_image_data.as_ref()),
+        last_modified: None,
+        script_hashes: vec![],
+        image_variants: vec![Item {
+            content: url_image_webp_data.clone(),
+            encoded: vec![],
+            content_type: HeaderValue::from_str("image/webp").unwrap(),
+            etag: make_etag(url_image_webp_data.as_ref()),
+            last_modified: None,
+            script_hashes: vec![],
+            image_variants: vec![],
+            cache_class: CacheClass::Asset,
+            children: HashMap::new(),
+        }],
+        cache_class: CacheClass::Asset,
         children: HashMap::new(),
     });
     root.to_mut()
@@ -198,43 +1078,107 @@ fn build_shared_state(mut posts: Vec<Post>, external_url_prefix: &String) -> Sha
     for x in &posts {
         let mut post_item: Cow<'static, Item> = Cow::Owned(Item {
             content: x.pre_rendered.clone(),
-            compressed: Cow::from(deflate_bytes(x.pre_rendered.as_ref())),
+            encoded: encode_variants(
+                &format!("/{}/", x.path),
+                HTML_CONTENT_TYPE,
+                x.pre_rendered.as_ref(),
+            ),
             content_type: HeaderValue::from_str(HTML_CONTENT_TYPE).unwrap(),
-            etag: make_hash(x.title.as_str(), "").to_string(),
+            etag: make_etag(x.pre_rendered.as_ref()),
+            last_modified: Some(x.date),
+            script_hashes: vec![make_csp_hash(&x.json_ld)],
+            image_variants: vec![],
+            cache_class: CacheClass::Post,
             children: HashMap::new(),
         });
 
-        for y in x.assets.clone() {
-            let asset_item = Cow::Owned(Item {
-                content: y.1.clone(),
-                compressed: Cow::from(deflate_bytes(y.1.as_ref())),
-                content_type: HeaderValue::from_str(
-                    mime_guess::from_path(y.0.as_str())
-                        .first_or_text_plain()
-                        .to_string()
-                        .as_str(),
-                )
-                .unwrap(),
-                etag: make_hash(x.title.as_str(), y.0.as_str()).to_string(),
+        let asset_item = |name: &String, data: &Cow<'static, [u8]>| {
+            let content_type = mime_guess::from_path(name.as_str())
+                .first_or_text_plain()
+                .to_string();
+            Item {
+                content: data.clone(),
+                encoded: encode_variants(
+                    &format!("/{}/{}", x.path, name),
+                    content_type.as_str(),
+                    data.as_ref(),
+                ),
+                content_type: HeaderValue::from_str(content_type.as_str()).unwrap(),
+                etag: make_etag(data.as_ref()),
+                last_modified: None,
+                script_hashes: vec![],
+                image_variants: vec![],
+                cache_class: CacheClass::Asset,
                 children: HashMap::new(),
-            });
-            post_item.to_mut().children.insert(y.0.clone(), asset_item);
+            }
+        };
+        let fingerprints = asset_fingerprints(&x.assets);
+        for y in x.assets.iter() {
+            let mut item = asset_item(y.0, y.1);
+            // images can have smaller alternatives embedded next to them that are served to clients that accept them
+            if item.content_type.as_bytes().starts_with(b"image/") {
+                item.image_variants = IMAGE_VARIANT_EXTENSIONS
+                    .iter()
+                    .filter_map(|extension| {
+                        let name = format!("{}.{}", y.0, extension);
+                        x.assets.get(&name).map(|data| asset_item(&name, data))
+                    })
+                    .collect();
+            }
+            post_item.to_mut().children.insert(
+                fingerprints[y.0].clone(),
+                Cow::Owned(Item {
+                    cache_class: CacheClass::Immutable,
+                    ..item.clone()
+                }),
+            );
+            post_item
+                .to_mut()
+                .children
+                .insert(y.0.clone(), Cow::Owned(item));
         }
 
+        let card_content = pre_render_social_card(&x.title, &x.date);
+        let card_item = Cow::Owned(Item {
+            content: card_content.clone(),
+            encoded: encode_variants(
+                &format!("/{}/{}", x.path, SOCIAL_CARD_FILE_NAME),
+                "image/png",
+                card_content.as_ref(),
+            ),
+            content_type: HeaderValue::from_str("image/png").unwrap(),
+            etag: make_etag(card_content.as_ref()),
+            last_modified: None,
+            script_hashes: vec![],
+            image_variants: vec![],
+            cache_class: CacheClass::Asset,
+            children: HashMap::new(),
+        });
+        post_item
+            .to_mut()
+            .children
+            .insert(SOCIAL_CARD_FILE_NAME.to_string(), card_item);
+
         root.to_mut().children.insert(x.path.clone(), post_item);
     }
 
     {
         let robots_content = Cow::from(
-            "User-agent: *\nAllow: /\nDisallow: /livez\nDisallow: /readyz\nDisallow: /metricz\n"
-                .as_bytes()
-                .to_owned(),
+            format!(
+                "User-agent: *\nAllow: {0}/\nDisallow: /livez\nDisallow: /readyz\nDisallow: /metricz\nDisallow: {0}/search\nSitemap: {1}/sitemap.xml\n",
+                base_path, external_url_prefix
+            )
+            .into_bytes(),
         );
         let robots = Cow::Owned(Item {
             content: robots_content.clone(),
-            compressed: Cow::from(deflate_bytes(robots_content.as_ref())),
+            encoded: encode_variants("/robots.txt", PLAIN_CONTENT_TYPE, robots_content.as_ref()),
             content_type: HeaderValue::from_str(PLAIN_CONTENT_TYPE).unwrap(),
-            etag: make_hash_of_bytes(robots_content.clone()).to_string(),
+            etag: make_etag(robots_content.as_ref()),
+            last_modified: None,
+            script_hashes: vec![],
+            image_variants: vec![],
+            cache_class: CacheClass::Feed,
             children: HashMap::new(),
         });
         root.to_mut()
@@ -243,39 +1187,160 @@ fn build_shared_state(mut posts: Vec<Post>, external_url_prefix: &String) -> Sha
     }
 
     {
-        let rss_content = pre_render_rss(&posts, &external_url_prefix);
+        let rss_content = pre_render_rss(&posts, external_url_prefix);
         let rss = Cow::Owned(Item {
             content: rss_content.clone(),
-            compressed: Cow::from(deflate_bytes(rss_content.as_ref())),
+            encoded: encode_variants("/rss.xml", "text/xml", rss_content.as_ref()),
             content_type: HeaderValue::from_str("text/xml").unwrap(),
-            etag: make_hash_of_bytes(rss_content.clone()).to_string(),
+            etag: make_etag(rss_content.as_ref()),
+            last_modified: None,
+            script_hashes: vec![],
+            image_variants: vec![],
+            cache_class: CacheClass::Feed,
             children: HashMap::new(),
         });
         root.to_mut().children.insert("rss.xml".to_string(), rss);
 
         let feed = Cow::Owned(Item {
             content: rss_content.clone(),
-            compressed: Cow::from(deflate_bytes(rss_content.as_ref())),
+            encoded: encode_variants("/feed.xml", "text/xml", rss_content.as_ref()),
             content_type: HeaderValue::from_str("text/xml").unwrap(),
-            etag: make_hash_of_bytes(rss_content.clone()).to_string(),
+            etag: make_etag(rss_content.as_ref()),
+            last_modified: None,
+            script_hashes: vec![],
+            image_variants: vec![],
+            cache_class: CacheClass::Feed,
             children: HashMap::new(),
         });
         root.to_mut().children.insert("feed.xml".to_string(), feed);
+
+        let atom_content = pre_render_atom(&posts, external_url_prefix);
+        let atom = Cow::Owned(Item {
+            content: atom_content.clone(),
+            encoded: encode_variants("/atom.xml", ATOM_CONTENT_TYPE, atom_content.as_ref()),
+            content_type: HeaderValue::from_str(ATOM_CONTENT_TYPE).unwrap(),
+            etag: make_etag(atom_content.as_ref()),
+            last_modified: None,
+            script_hashes: vec![],
+            image_variants: vec![],
+            cache_class: CacheClass::Feed,
+            children: HashMap::new(),
+        });
+        root.to_mut().children.insert("atom.xml".to_string(), atom);
     }
 
-    let not_found_content = pre_render_not_found();
-    let not_found = Cow::Owned(Item {
-        content: not_found_content.clone(),
-        compressed: Cow::from(deflate_bytes(not_found_content.as_ref())),
-        content_type: HeaderValue::from_str(HTML_CONTENT_TYPE).unwrap(),
-        etag: make_hash("", "").to_string(),
-        children: HashMap::new(),
+    root.to_mut().children.insert(
+        "archive".to_string(),
+        build_archive_item(&posts, external_url_prefix),
+    );
+
+    {
+        let opensearch_content = pre_render_opensearch(external_url_prefix);
+        let opensearch = Cow::Owned(Item {
+            content: opensearch_content.clone(),
+            encoded: encode_variants(
+                "/opensearch.xml",
+                "application/opensearchdescription+xml",
+                opensearch_content.as_ref(),
+            ),
+            content_type: HeaderValue::from_str("application/opensearchdescription+xml").unwrap(),
+            etag: make_etag(opensearch_content.as_ref()),
+            last_modified: None,
+            script_hashes: vec![],
+            image_variants: vec![],
+            cache_class: CacheClass::Feed,
+            children: HashMap::new(),
+        });
+        root.to_mut()
+            .children
+            .insert("opensearch.xml".to_string(), opensearch);
+    }
+
+    for (name, sitemap_content) in pre_render_sitemaps(&root, external_url_prefix) {
+        let sitemap = Cow::Owned(Item {
+            content: sitemap_content.clone(),
+            encoded: encode_variants(&format!("/{}", name), "text/xml", sitemap_content.as_ref()),
+            content_type: HeaderValue::from_str("text/xml").unwrap(),
+            etag: make_etag(sitemap_content.as_ref()),
+            last_modified: None,
+            script_hashes: vec![],
+            image_variants: vec![],
+            cache_class: CacheClass::Feed,
+            children: HashMap::new(),
+        });
+        root.to_mut().children.insert(name, sitemap);
+    }
+
+    let not_found = build_error_item(StatusCode::NOT_FOUND, base_path);
+    let gone = build_error_item(StatusCode::GONE, base_path);
+
+    // redirects given on the cli win over the embedded ones, which win over the aliases of posts and then the first
+    // index page, which is served at the root rather than under /page/
+    let mut redirects = options.redirects.clone();
+    redirects.extend(
+        parse_redirects(from_utf8(&Asset::get(REDIRECTS_FILE_NAME).unwrap().data).unwrap())
+            .unwrap_or_else(|e| panic!("invalid {}: {}", REDIRECTS_FILE_NAME, e)),
+    );
+    for x in &posts {
+        for alias in x.aliases.iter() {
+            redirects.push(Redirect {
+                from: format!("/{}", alias.trim_start_matches('/')),
+                to: Some(format!("/{}/", x.path)),
+                status: StatusCode::MOVED_PERMANENTLY,
+            });
+        }
+    }
+    redirects.push(Redirect {
+        from: "/page/1/".to_string(),
+        to: Some(index_page_path(1)),
+        status: StatusCode::MOVED_PERMANENTLY,
     });
+    let problems = validate_redirects(&root, &redirects);
+    if !problems.is_empty() {
+        panic!("invalid redirects: {}", problems.join(", "));
+    }
+    let problems = validate_paths(base_path, &root);
+    if !problems.is_empty() {
+        panic!("invalid paths: {}", problems.join(", "));
+    }
+
+    let state_bytes = item_bytes(&root) + item_bytes(&not_found) + item_bytes(&gone);
+    SharedState {
+        root,
+        redirects,
+        canonical_redirect_status: options.canonical_redirect_status,
+        canonical_prefix: Some(external_url_prefix.clone())
+            .filter(|p| options.canonical_host_redirect && !p.is_empty()),
+        cache_policies: options.cache_policies.clone(),
+        strict_transport_security: options
+            .strict_transport_security
+            .as_ref()
+            .map(|v| HeaderValue::from_str(v).expect("invalid strict transport security header")),
+        draining: Arc::new(AtomicBool::new(false)),
+        watchdog: Watchdog::default(),
+        post_count: posts.len(),
+        state_bytes,
+        metrics: Mutex::new(Metrics::default()),
+        not_found,
+        gone,
+        search_index: build_search_index(&posts),
+        head: pre_render_head(base_path),
+        base_path: base_path.to_string(),
+        footer: pre_render_footer(),
+    }
+}
 
-    SharedState { root, not_found }
+/// The path the site is mounted at, taken from the external url prefix: /blog for https://example.com/blog, or empty
+/// when the site is served from the root.
+fn url_base_path(external_url_prefix: &str) -> &str {
+    match external_url_prefix.split_once("://") {
+        Some((_, rest)) => rest.find('/').map(|i| &rest[i..]).unwrap_or_default(),
+        None => external_url_prefix,
+    }
+    .trim_end_matches('/')
 }
 
-fn pre_render_head() -> PreEscaped<String> {
+fn pre_render_head(base_path: &str) -> PreEscaped<String> {
     let css1 = from_utf8(Asset::get("normalize.css").unwrap().data.as_ref())
         .unwrap()
         .to_owned();
@@ -285,7 +1350,9 @@ fn pre_render_head() -> PreEscaped<String> {
     let tree = html! {
         link rel="shortcut icon" href=(ENCODED_FAVICON) type="image/svg+xml";
         link rel="me" href="https://hachyderm.io/@benmeier_";
-        link rel="alternate" href="/feed.xml" type="application/rss+xml" title="RSS feed";
+        link rel="alternate" href={ (base_path) "/feed.xml" } type="application/rss+xml" title="RSS feed";
+        link rel="alternate" href={ (base_path) "/atom.xml" } type="application/atom+xml" title="Atom feed";
+        link rel="search" href={ (base_path) "/opensearch.xml" } type="application/opensearchdescription+xml" title="Ben's Blog";
         meta charset="utf-8";
         meta name="author" content="Ben Meier";
         meta name="keywords" content="golang, rust, distributed systems, programming, security";
@@ -302,21 +1369,24 @@ fn pre_render_head() -> PreEscaped<String> {
             ".footnote-definition p { display: inline; }"
             "header.row { justify-content: space-between; }"
             "header.row section.column { max-width: fit-content; }"
+            ".search-form { margin: 0; } "
+            ".search-form input { margin: 0; } "
+            "mark { background-color: #f9ebb2; } "
         }
     };
     tree.clone()
 }
 
+/// The footer is part of every pre-rendered page, so it must not vary between restarts or replicas or the etags
+/// would too. The pid and start time of the process are reported by /readyz?verbose instead.
 fn pre_render_footer() -> PreEscaped<String> {
-    let now = OffsetDateTime::now_utc();
-    let pid = std::process::id();
     let name = clap::crate_name!();
     html! {
         footer.row {
             section.column {
                 hr {}
                 p {
-                    "© Ben Meier " (now.year())
+                    "© Ben Meier " (*COPYRIGHT_YEAR)
                     br;
                     small {
                         "This blog is a single Rust binary with all assets embedded and pre-rendered. "
@@ -329,7 +1399,6 @@ fn pre_render_footer() -> PreEscaped<String> {
                     br;
                     small {
                         "name=" (name) " version=" (CRATE_VERSION)
-                        " pid=" (pid) " start-time=" (now.format(&FOOTER_DATE_FORMAT).unwrap().to_string())
                     }
                 }
             }
@@ -337,43 +1406,91 @@ fn pre_render_footer() -> PreEscaped<String> {
     }
 }
 
-fn pre_render_index(posts: &Vec<Post>, external_url_prefix: &String) -> Cow<'static, [u8]> {
-    let tree = html! {
-        (DOCTYPE)
+fn pre_render_index(
+    posts: &[Post],
+    page: usize,
+    options: &SiteOptions,
+    external_url_prefix: &String,
+) -> Cow<'static, [u8]> {
+    let base_path = url_base_path(external_url_prefix);
+    let dated_posts = dated_index_posts(posts);
+    let page_count = index_page_count(dated_posts.len(), options);
+    let page_posts: Vec<&Post> = if options.index_page_size == 0 {
+        dated_posts
+    } else {
+        dated_posts
+            .into_iter()
+            .skip((page - 1) * options.index_page_size)
+            .take(options.index_page_size)
+            .collect()
+    };
+    let featured: Vec<&Post> = if page == 1 {
+        posts.iter().filter(|x| x.featured).collect()
+    } else {
+        vec![]
+    };
+    let title = if page == 1 {
+        "Ben's Blog".to_string()
+    } else {
+        format!("Ben's Blog - Page {}", page)
+    };
+    let tree = html! {
+        (DOCTYPE)
         html lang="en" {
             head {
-                title { "Ben's Blog" }
+                title { (title) }
                 meta name="description" content="Technical blog of Ben Meier";
                 meta property="og:type" content="website";
-                meta property="og:title" content="Ben's Blog";
+                meta property="og:title" content=(title);
                 meta property="og:description" content="Technical blog of Ben Meier";
-                meta property="og:url" content={ (external_url_prefix) "/" };
+                meta property="og:url" content={ (external_url_prefix) (index_page_path(page)) };
                 meta property="og:image" content={ (external_url_prefix) "/url-image.jpg" };
-                (pre_render_head())
+                link rel="canonical" href={ (external_url_prefix) (index_page_path(page)) };
+                @if page > 1 {
+                    link rel="prev" href={ (base_path) (index_page_path(page - 1)) };
+                }
+                @if page < page_count {
+                    link rel="next" href={ (base_path) (index_page_path(page + 1)) };
+                }
+                meta name="twitter:card" content="summary";
+                meta name="twitter:title" content=(title);
+                meta name="twitter:description" content="Technical blog of Ben Meier";
+                meta name="twitter:image" content={ (external_url_prefix) "/url-image.jpg" };
+                @if page == 1 {
+                    script type="application/ld+json" { (PreEscaped(render_index_json_ld(posts, external_url_prefix))) }
+                }
+                (pre_render_head(base_path))
             }
             body {
                 div.container {
-                    header.row {
+                    header.row.h-card {
                         section class="column" {
-                            h1 { "Ben Meier" }
+                            h1 {
+                                a.p-name.u-url href={ (external_url_prefix) "/" } { "Ben Meier" }
+                            }
                         }
                         section class="column" {
                             "Mastodon: "
-                            a href="https://hachyderm.io/@benmeier_" {
+                            a.u-url rel="me" href="https://hachyderm.io/@benmeier_" {
                                 "@benmeier_@hachyderm.io"
                             }
                             " | Github: "
-                            a href="https://github.com/astromechza" {
+                            a.u-url rel="me" href="https://github.com/astromechza" {
                                 "astromechza"
                             }
                             " | rss: "
-                            a href="/feed.xml" target="_blank" {
+                            a href={ (base_path) "/feed.xml" } target="_blank" {
                                 "feed.xml"
                             }
                             " | "
-                            a href="/" {
+                            a href={ (base_path) "/" } {
                                 "All Posts"
                             }
+                            " | "
+                            a href={ (base_path) "/archive/" } {
+                                "Archive"
+                            }
+                            (pre_render_search_form("", base_path))
                         }
                     }
                     main.row {
@@ -387,30 +1504,33 @@ fn pre_render_index(posts: &Vec<Post>, external_url_prefix: &String) -> Cow<'sta
                                 }
                             }
                             hr {}
-                            nav {
-                                (PreEscaped("<ul class=\"index-nav-ul\">"))
-                                @let mut last_year = 0;
-                                @for x in posts.iter() {
-                                    @if x.date.year() != last_year {
-                                        (PreEscaped("</ul>"))
-                                        h2 {
-                                            ({
-                                                last_year = x.date.year();
-                                                last_year
-                                            })
-                                        }
-                                        (PreEscaped("<ul class=\"index-nav-ul\">"))
+                            @if !featured.is_empty() {
+                                nav.h-feed.featured {
+                                    h2 { "Featured" }
+                                    (pre_render_post_links(&featured, true, base_path))
+                                }
+                            }
+                            nav.h-feed {
+                                @for (year, year_posts) in group_posts_by_year(&page_posts) {
+                                    h2 {
+                                        a href={ (base_path) "/archive/" (year) "/" } { (year) }
                                     }
-                                    li {
-                                        p {
-                                            a href={ (x.path) "/" } {
-                                                time datetime=(x.date.format(&RFC3339_DATE_FORMAT).unwrap().to_string()) { (x.date.format(&POST_DATE_FORMAT).unwrap().to_string()) }
-                                                (": ") (x.title)
-                                            }
-                                        }
+                                    (pre_render_post_links(&year_posts, options.index_summaries, base_path))
+                                }
+                            }
+                            @if page_count > 1 {
+                                hr {}
+                                nav.pagination {
+                                    @if page > 1 {
+                                        a rel="prev" href={ (base_path) (index_page_path(page - 1)) } { "← Newer posts" }
+                                        " | "
+                                    }
+                                    "Page " (page) " of " (page_count)
+                                    @if page < page_count {
+                                        " | "
+                                        a rel="next" href={ (base_path) (index_page_path(page + 1)) } { "Older posts →" }
                                     }
                                 }
-                                (PreEscaped("</ul>"))
                             }
                         }
                     }
@@ -419,13 +1539,230 @@ fn pre_render_index(posts: &Vec<Post>, external_url_prefix: &String) -> Cow<'sta
             }
         }
     };
-    Cow::from(tree.into_string().as_bytes().to_owned()).to_owned()
+    Cow::from(tree.into_string().as_bytes().to_owned())
+}
+
+/// Group posts by the year they were published in, newest year first, keeping the order of posts within each year.
+fn group_posts_by_year<'a>(posts: &[&'a Post]) -> Vec<(i32, Vec<&'a Post>)> {
+    let mut groups: BTreeMap<i32, Vec<&Post>> = BTreeMap::new();
+    for x in posts.iter() {
+        groups.entry(x.date.year()).or_default().push(x);
+    }
+    groups.into_iter().rev().collect()
+}
+
+/// Group posts by the month they were published in, newest month first, keeping the order of posts within each month.
+fn group_posts_by_month<'a>(posts: &[&'a Post]) -> Vec<(time::Month, Vec<&'a Post>)> {
+    let mut groups: BTreeMap<u8, Vec<&Post>> = BTreeMap::new();
+    for x in posts.iter() {
+        groups.entry(x.date.month() as u8).or_default().push(x);
+    }
+    groups
+        .into_iter()
+        .rev()
+        .map(|(m, v)| (time::Month::try_from(m).unwrap(), v))
+        .collect()
+}
+
+fn pre_render_post_links(posts: &[&Post], summaries: bool, base_path: &str) -> Markup {
+    html! {
+        ul.index-nav-ul {
+            @for x in posts.iter() {
+                li.h-entry {
+                    p {
+                        a.u-url href={ (base_path) "/" (x.path) "/" } {
+                            time.dt-published datetime=(x.date.format(&RFC3339_DATE_FORMAT).unwrap().to_string()) { (x.date.format(&POST_DATE_FORMAT).unwrap().to_string()) }
+                            (": ") span.p-name { (x.title) }
+                        }
+                        @if summaries {
+                            br;
+                            small.p-summary { (x.description) }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn index_page_count(post_count: usize, options: &SiteOptions) -> usize {
+    if options.index_page_size == 0 {
+        return 1;
+    }
+    post_count.div_ceil(options.index_page_size).max(1)
+}
+
+/// The posts listed by date on the index pages, leaving out the featured posts which are listed above them on the
+/// first page instead.
+fn dated_index_posts(posts: &[Post]) -> Vec<&Post> {
+    posts.iter().filter(|x| !x.featured).collect()
+}
+
+fn index_page_path(page: usize) -> String {
+    if page <= 1 {
+        "/".to_string()
+    } else {
+        format!("/page/{}/", page)
+    }
+}
+
+/// Build the archive item tree: a page listing every year, a page per year, and a page per month within each year.
+fn build_archive_item(posts: &[Post], external_url_prefix: &String) -> Cow<'static, Item> {
+    let base_path = url_base_path(external_url_prefix);
+    let all: Vec<&Post> = posts.iter().collect();
+    let years = group_posts_by_year(&all);
+    let content = pre_render_archive(
+        "Archive",
+        "/archive/",
+        html! {
+            ul.index-nav-ul {
+                @for (year, year_posts) in years.iter() {
+                    li {
+                        p {
+                            a href={ (base_path) "/archive/" (year) "/" } { (year) }
+                            " (" (year_posts.len()) " posts)"
+                        }
+                    }
+                }
+            }
+        },
+        external_url_prefix,
+    );
+    let mut archive: Cow<'static, Item> = Cow::Owned(Item {
+        content: content.clone(),
+        encoded: encode_variants("/archive/", HTML_CONTENT_TYPE, content.as_ref()),
+        content_type: HeaderValue::from_str(HTML_CONTENT_TYPE).unwrap(),
+        etag: make_etag(content.as_ref()),
+        last_modified: posts.iter().map(|x| x.date).max(),
+        script_hashes: vec![],
+        image_variants: vec![],
+        cache_class: CacheClass::Index,
+        children: HashMap::new(),
+    });
+
+    for (year, year_posts) in years.iter() {
+        let months = group_posts_by_month(year_posts);
+        let content = pre_render_archive(
+            &format!("Posts from {}", year),
+            &format!("/archive/{}/", year),
+            html! {
+                @for (month, month_posts) in months.iter() {
+                    h3 {
+                        a href={ (base_path) "/archive/" (year) "/" (format!("{:02}", *month as u8)) "/" } { (month) }
+                    }
+                    (pre_render_post_links(month_posts, false, base_path))
+                }
+            },
+            external_url_prefix,
+        );
+        let mut year_item: Cow<'static, Item> = Cow::Owned(Item {
+            content: content.clone(),
+            encoded: encode_variants(
+                &format!("/archive/{}/", year),
+                HTML_CONTENT_TYPE,
+                content.as_ref(),
+            ),
+            content_type: HeaderValue::from_str(HTML_CONTENT_TYPE).unwrap(),
+            etag: make_etag(content.as_ref()),
+            last_modified: year_posts.iter().map(|x| x.date).max(),
+            script_hashes: vec![],
+            image_variants: vec![],
+            cache_class: CacheClass::Index,
+            children: HashMap::new(),
+        });
+
+        for (month, month_posts) in months.iter() {
+            let content = pre_render_archive(
+                &format!("Posts from {} {}", month, year),
+                &format!("/archive/{}/{:02}/", year, *month as u8),
+                pre_render_post_links(month_posts, false, base_path),
+                external_url_prefix,
+            );
+            let month_item = Cow::Owned(Item {
+                content: content.clone(),
+                encoded: encode_variants(
+                    &format!("/archive/{}/{:02}/", year, *month as u8),
+                    HTML_CONTENT_TYPE,
+                    content.as_ref(),
+                ),
+                content_type: HeaderValue::from_str(HTML_CONTENT_TYPE).unwrap(),
+                etag: make_etag(content.as_ref()),
+                last_modified: month_posts.iter().map(|x| x.date).max(),
+                script_hashes: vec![],
+                image_variants: vec![],
+                cache_class: CacheClass::Index,
+                children: HashMap::new(),
+            });
+            year_item
+                .to_mut()
+                .children
+                .insert(format!("{:02}", *month as u8), month_item);
+        }
+
+        archive
+            .to_mut()
+            .children
+            .insert(year.to_string(), year_item);
+    }
+    archive
+}
+
+fn pre_render_archive(
+    heading: &str,
+    path: &str,
+    body: Markup,
+    external_url_prefix: &String,
+) -> Cow<'static, [u8]> {
+    let base_path = url_base_path(external_url_prefix);
+    let tree = html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                title { (heading) " - Ben's Blog" }
+                meta name="description" content={ (heading) " on the technical blog of Ben Meier" };
+                meta property="og:type" content="website";
+                meta property="og:title" content={ (heading) " - Ben's Blog" };
+                meta property="og:url" content={ (external_url_prefix) (path) };
+                meta property="og:image" content={ (external_url_prefix) "/url-image.jpg" };
+                link rel="canonical" href={ (external_url_prefix) (path) };
+                (pre_render_head(base_path))
+            }
+            body {
+                div.container {
+                    header.row {
+                        section class="column" {
+                            h1 { (heading) }
+                        }
+                        section class="column" {
+                            a href={ (base_path) "/" } {
+                                "All Posts"
+                            }
+                            " | "
+                            a href={ (base_path) "/archive/" } {
+                                "Archive"
+                            }
+                            (pre_render_search_form("", base_path))
+                        }
+                    }
+                    main.row {
+                        section.column {
+                            nav.h-feed {
+                                (body)
+                            }
+                        }
+                    }
+                    (pre_render_footer())
+                }
+            }
+        }
+    };
+    Cow::from(tree.into_string().as_bytes().to_owned())
 }
 
-fn pre_render_rss(posts: &Vec<Post>, external_url_prefix: &String) -> Cow<'static, [u8]> {
+fn pre_render_rss(posts: &[Post], external_url_prefix: &String) -> Cow<'static, [u8]> {
     let tree = html! {
         (PreEscaped("<?xml version=\"1.0\" encoding=\"UTF-8\" ?>"))
-        rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom" {
+        rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom" xmlns:content="http://purl.org/rss/1.0/modules/content/" {
             channel {
                 title { "Ben Meier" }
                 link { (external_url_prefix) "/" }
@@ -441,58 +1778,323 @@ fn pre_render_rss(posts: &Vec<Post>, external_url_prefix: &String) -> Cow<'stati
                         guid { (external_url_prefix) "/" (x.path) "/" }
                         pubDate { (x.date.format(&RFC2822_DATE_FORMAT).unwrap().to_string()) }
                         category { "IT/Technical" }
+                        content:encoded { (absolutize_html_links(&x.html, external_url_prefix, &x.path)) }
+                    }
+                }
+            }
+        }
+    };
+    Cow::from(tree.into_string().as_bytes().to_owned())
+}
+
+fn pre_render_atom(posts: &[Post], external_url_prefix: &String) -> Cow<'static, [u8]> {
+    let updated = posts
+        .iter()
+        .map(|x| x.date)
+        .max()
+        .unwrap_or(PrimitiveDateTime::new(
+            OffsetDateTime::now_utc().date(),
+            time!(0:00),
+        ));
+    let tree = html! {
+        (PreEscaped("<?xml version=\"1.0\" encoding=\"UTF-8\" ?>"))
+        feed xmlns="http://www.w3.org/2005/Atom" {
+            title { "Ben Meier" }
+            subtitle { "I'm a software engineer working mostly on distributed systems with an interest in security, networking, correctness, and chaos." }
+            id { (external_url_prefix) "/" }
+            link href={ (external_url_prefix) "/" };
+            link rel="self" href={ (external_url_prefix) "/atom.xml" };
+            updated { (updated.format(&RFC3339_DATE_FORMAT).unwrap().to_string()) }
+            author {
+                name { "Ben Meier" }
+            }
+            @for x in posts.iter() {
+                entry {
+                    title { (x.title) }
+                    id { (external_url_prefix) "/" (x.path) "/" }
+                    link href={ (external_url_prefix) "/" (x.path) "/" };
+                    published { (x.date.format(&RFC3339_DATE_FORMAT).unwrap().to_string()) }
+                    updated { (x.date.format(&RFC3339_DATE_FORMAT).unwrap().to_string()) }
+                    category term="IT/Technical";
+                    content type="html" { (absolutize_html_links(&x.html, external_url_prefix, &x.path)) }
+                }
+            }
+        }
+    };
+    Cow::from(tree.into_string().as_bytes().to_owned())
+}
+
+/// Rewrite every relative href and src attribute in the rendered html of a post into an absolute url so that the
+/// content still works when it is read outside of the blog, for example in a feed reader.
+fn absolutize_html_links(html: &str, external_url_prefix: &str, path: &str) -> String {
+    lazy_static! {
+        static ref LINK_RE: regex::Regex = regex::Regex::new(r#"\b(href|src)="([^"]*)""#).unwrap();
+    }
+    LINK_RE
+        .replace_all(html, |c: &regex::Captures| {
+            format!(
+                "{}=\"{}\"",
+                &c[1],
+                absolutize_url(&c[2], external_url_prefix, path)
+            )
+        })
+        .to_string()
+}
+
+/// Resolve a url found in the post at the given path into an absolute url using the external url prefix. Urls which
+/// already have a scheme or are protocol relative are returned unchanged, and root-relative urls are taken to already
+/// include the base path.
+fn absolutize_url(url: &str, external_url_prefix: &str, path: &str) -> String {
+    // a scheme is a letter followed by letters, digits, '+', '-' or '.' before the first ':', see RFC 3986 section 3.1
+    let has_scheme = url.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    });
+    if has_scheme || url.starts_with("//") {
+        return url.to_string();
+    }
+    if url.starts_with('#') {
+        return format!("{}/{}/{}", external_url_prefix, path, url);
+    }
+    let (url_path, suffix) = match url.find(['?', '#']) {
+        Some(i) => url.split_at(i),
+        None => (url, ""),
+    };
+    let mut parts: Vec<&str> = Vec::new();
+    if !url_path.starts_with('/') {
+        parts.push(path);
+    }
+    for segment in url_path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(segment),
+        }
+    }
+    let base = match url_path.starts_with('/') {
+        true => external_url_prefix
+            .strip_suffix(url_base_path(external_url_prefix))
+            .unwrap_or(external_url_prefix),
+        false => external_url_prefix,
+    };
+    let mut out = format!("{}/{}", base, parts.join("/"));
+    if url_path.ends_with('/') && !parts.is_empty() {
+        out.push('/');
+    }
+    out.push_str(suffix);
+    out
+}
+
+/// Render the sitemap from the html pages in the item tree. Each page lists the images served next to it. When there
+/// are more urls than a single sitemap may contain, they are split across numbered sitemaps and sitemap.xml becomes a
+/// sitemap index pointing at them.
+fn pre_render_sitemaps(
+    root: &Item,
+    external_url_prefix: &String,
+) -> Vec<(String, Cow<'static, [u8]>)> {
+    let is_html = |x: &Item| {
+        x.content_type
+            .to_str()
+            .map(|s| s.eq(HTML_CONTENT_TYPE))
+            .unwrap_or_default()
+    };
+    let is_image = |x: &Item| {
+        x.content_type
+            .to_str()
+            .map(|s| s.starts_with("image/"))
+            .unwrap_or_default()
+    };
+
+    fn collect_pages<'a>(
+        path: String,
+        x: &'a Item,
+        is_html: &dyn Fn(&Item) -> bool,
+        is_image: &dyn Fn(&Item) -> bool,
+        pages: &mut Vec<(String, &'a Item, Vec<String>)>,
+    ) {
+        let mut keys: Vec<&String> = x.children.keys().collect();
+        keys.sort();
+        // assets are served under both their plain and fingerprinted names, only list the one the pages link to
+        let images =
+            keys.iter()
+                .filter(|k| {
+                    let y = x.children.get(**k).unwrap();
+                    is_image(y)
+                        && (y.cache_class == CacheClass::Immutable
+                            || !x.children.values().any(|z| {
+                                z.cache_class == CacheClass::Immutable && z.etag == y.etag
+                            }))
+                })
+                .map(|k| k.to_string())
+                .collect();
+        pages.push((path.clone(), x, images));
+        for k in keys {
+            let y = x.children.get(k).unwrap();
+            if is_html(y) {
+                collect_pages(format!("{}{}/", path, k), y, is_html, is_image, pages);
+            }
+        }
+    }
+    let mut pages = Vec::new();
+    collect_pages("/".to_string(), root, &is_html, &is_image, &mut pages);
+
+    let render_urls = |pages: &[(String, &Item, Vec<String>)]| {
+        let tree = html! {
+            (PreEscaped("<?xml version=\"1.0\" encoding=\"UTF-8\" ?>"))
+            urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9" xmlns:image="http://www.google.com/schemas/sitemap-image/1.1" {
+                @for (path, x, images) in pages.iter() {
+                    url {
+                        loc { (external_url_prefix) (path) }
+                        @if let Some(lm) = x.last_modified {
+                            lastmod { (lm.format(&RFC3339_DATE_FORMAT).unwrap().to_string()) }
+                        }
+                        @for k in images {
+                            image:image {
+                                image:loc { (external_url_prefix) (path) (k) }
+                            }
+                        }
                     }
                 }
             }
+        };
+        Cow::from(tree.into_string().into_bytes())
+    };
+
+    if pages.len() <= SITEMAP_MAX_URLS {
+        return vec![("sitemap.xml".to_string(), render_urls(&pages))];
+    }
+
+    let mut out: Vec<(String, Cow<'static, [u8]>)> = pages
+        .chunks(SITEMAP_MAX_URLS)
+        .enumerate()
+        .map(|(i, chunk)| (format!("sitemap-{}.xml", i + 1), render_urls(chunk)))
+        .collect();
+    let index = html! {
+        (PreEscaped("<?xml version=\"1.0\" encoding=\"UTF-8\" ?>"))
+        sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9" {
+            @for (name, _) in out.iter() {
+                sitemap {
+                    loc { (external_url_prefix) "/" (name) }
+                }
+            }
         }
     };
-    Cow::from(tree.into_string().as_bytes().to_owned()).to_owned()
+    out.push((
+        "sitemap.xml".to_string(),
+        Cow::from(index.into_string().into_bytes()),
+    ));
+    out
+}
+
+/// Render a titled list of links to other posts at the bottom of a post, or nothing when the list is empty.
+fn pre_render_post_list(class: &str, heading: &str, posts: &[&Post], base_path: &str) -> Markup {
+    html! {
+        @if !posts.is_empty() {
+            hr {}
+            section class=(class) {
+                h3 { (heading) }
+                ul.index-nav-ul {
+                    @for x in posts.iter() {
+                        li {
+                            p {
+                                a href={ (base_path) "/" (x.path) "/" } {
+                                    time datetime=(x.date.format(&RFC3339_DATE_FORMAT).unwrap().to_string()) { (x.date.format(&POST_DATE_FORMAT).unwrap().to_string()) }
+                                    (": ") (x.title)
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
 
 fn pre_render_post(
-    title: &String,
-    time: &PrimitiveDateTime,
-    content: &PreEscaped<String>,
+    post: &Post,
+    posts: &[Post],
     external_url_prefix: &String,
-    path: &String,
 ) -> Cow<'static, [u8]> {
+    let base_path = url_base_path(external_url_prefix);
+    let related: Vec<&Post> = post
+        .related
+        .iter()
+        .filter_map(|r| posts.iter().find(|x| x.path.eq(r)))
+        .collect();
+    let backlinks: Vec<&Post> = post
+        .backlinks
+        .iter()
+        .filter_map(|r| posts.iter().find(|x| x.path.eq(r)))
+        .collect();
+    let title = &post.title;
+    let time = &post.date;
+    let content = PreEscaped(post.html.as_str());
+    let url = format!("{}/{}/", external_url_prefix, post.path);
+    let canonical = post.canonical.clone().unwrap_or(url);
+    let image = post.image.clone().unwrap_or(format!(
+        "{}/{}/{}",
+        external_url_prefix, post.path, SOCIAL_CARD_FILE_NAME
+    ));
     let tree = html! {
         (DOCTYPE)
         html lang="en" {
             head {
                 title { (title) }
-                meta name="description" content=(title);
+                link rel="canonical" href=(canonical);
+                meta name="description" content=(post.description);
                 meta property="og:type" content="article";
                 meta property="og:title" content=(title);
-                meta property="og:description" content={ (time.format(&POST_DATE_FORMAT).unwrap().to_string()) " - " (title) };
-                meta property="og:url" content={ (external_url_prefix) "/" (path) "/" };
-                meta property="og:image" content={ (external_url_prefix) "/url-image.jpg" };
+                meta property="og:description" content=(post.description);
+                meta property="og:url" content=(canonical);
+                meta property="og:image" content=(image);
                 meta property="article:author" content="Ben Meier";
                 meta property="article:published_time" content=(time.format(&RFC3339_DATE_FORMAT).unwrap().to_string());
-                (pre_render_head())
+                @for tag in post.tags.iter() {
+                    meta property="article:tag" content=(tag);
+                }
+                @if post.image.is_none() {
+                    meta property="og:image:width" content=(SOCIAL_CARD_WIDTH);
+                    meta property="og:image:height" content=(SOCIAL_CARD_HEIGHT);
+                }
+                meta name="twitter:card" content="summary_large_image";
+                meta name="twitter:title" content=(title);
+                meta name="twitter:description" content=(post.description);
+                meta name="twitter:image" content=(image);
+                script type="application/ld+json" { (PreEscaped(&post.json_ld)) }
+                (pre_render_head(base_path))
             }
             body {
-                div.container {
+                div.container.h-entry {
                     header.row {
                         section class="column" {
-                            h1 { (title) }
+                            h1.p-name { (title) }
                         }
                         section class="column" {
-                            a href="/" {
+                            a href={ (base_path) "/" } {
                                 "All Posts"
                             }
+                            (pre_render_search_form("", base_path))
                         }
                     }
                     main.row {
                         section.column {
                             small {
-                                "Ben Meier - "
-                                time datetime=(time.format(&RFC3339_DATE_FORMAT).unwrap().to_string()) { (time.format(&POST_DATE_FORMAT).unwrap().to_string()) }
+                                a.p-author.h-card href={ (external_url_prefix) "/" } { "Ben Meier" }
+                                " - "
+                                a.u-url href=(canonical) {
+                                    time.dt-published datetime=(time.format(&RFC3339_DATE_FORMAT).unwrap().to_string()) { (time.format(&POST_DATE_FORMAT).unwrap().to_string()) }
+                                }
                             }
                             hr {}
-                            article {
+                            article.e-content {
                                 (content)
                             }
+                            (pre_render_post_list("backlinks", "Referenced by", &backlinks, base_path))
+                            (pre_render_post_list("related-posts", "Related posts", &related, base_path))
                         }
                     }
                     (pre_render_footer())
@@ -500,191 +2102,637 @@ fn pre_render_post(
             }
         }
     };
-    Cow::from(tree.into_string().as_bytes().to_owned()).to_owned()
+    Cow::from(tree.into_string().as_bytes().to_owned())
+}
+
+fn pre_render_search_form(query: &str, base_path: &str) -> Markup {
+    html! {
+        form.search-form action={ (base_path) "/search" } method="get" role="search" {
+            input type="search" name="q" value=(query) placeholder="Search posts" aria-label="Search posts";
+        }
+    }
 }
 
-fn pre_render_not_found() -> Cow<'static, [u8]> {
+fn pre_render_opensearch(external_url_prefix: &String) -> Cow<'static, [u8]> {
     let tree = html! {
+        (PreEscaped("<?xml version=\"1.0\" encoding=\"UTF-8\" ?>"))
+        OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/" {
+            ShortName { "Ben's Blog" }
+            Description { "Search the technical blog of Ben Meier" }
+            InputEncoding { "UTF-8" }
+            Url type="text/html" method="get" template={ (external_url_prefix) "/search?q={searchTerms}" };
+        }
+    };
+    Cow::from(tree.into_string().as_bytes().to_owned())
+}
+
+/// Split text into lowercase alphanumeric terms along with the byte range each term was found at.
+fn tokenize_with_offsets(text: &str) -> Vec<(usize, usize, String)> {
+    let mut out = Vec::new();
+    let mut start: Option<usize> = None;
+    for (i, c) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+    {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(st)) => {
+                if i - st > 1 {
+                    out.push((st, i, text[st..i].to_lowercase()));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    tokenize_with_offsets(text)
+        .into_iter()
+        .map(|(_, _, t)| t)
+        .collect()
+}
+
+fn build_search_index(posts: &[Post]) -> SearchIndex {
+    let mut terms: HashMap<String, Vec<(usize, u32)>> = HashMap::new();
+    let documents: Vec<SearchDocument> = posts
+        .iter()
+        .map(|x| SearchDocument {
+            path: x.path.clone(),
+            title: x.title.clone(),
+            date: x.date,
+            text: x.text.split_whitespace().collect::<Vec<&str>>().join(" "),
+        })
+        .collect();
+    for (i, doc) in documents.iter().enumerate() {
+        for (t, c) in term_counts(&doc.title, &doc.text) {
+            terms.entry(t).or_default().push((i, c));
+        }
+    }
+    tracing::info!(
+        "Built search index with {} terms over {} documents",
+        terms.len(),
+        documents.len()
+    );
+    SearchIndex { documents, terms }
+}
+
+/// Rank the documents matching any of the query terms using tf-idf, most relevant first.
+fn search_documents<'a>(index: &'a SearchIndex, query: &str) -> Vec<&'a SearchDocument> {
+    let mut scores: HashMap<usize, f64> = HashMap::new();
+    let n = index.documents.len() as f64;
+    let mut query_terms = tokenize(query);
+    query_terms.sort();
+    query_terms.dedup();
+    for t in query_terms {
+        if let Some(postings) = index.terms.get(&t) {
+            let idf = (1.0 + n / postings.len() as f64).ln();
+            for (i, c) in postings {
+                *scores.entry(*i).or_default() += (1.0 + (*c as f64).ln()) * idf;
+            }
+        }
+    }
+    let mut ranked: Vec<(usize, f64)> = scores.into_iter().collect();
+    ranked.sort_by(|a, b| {
+        b.1.total_cmp(&a.1)
+            .then(index.documents[b.0].date.cmp(&index.documents[a.0].date))
+    });
+    ranked
+        .into_iter()
+        .take(SEARCH_MAX_RESULTS)
+        .map(|(i, _)| &index.documents[i])
+        .collect()
+}
+
+/// Build a snippet of the text around the first match of the query terms, split into segments where the bool
+/// indicates whether the segment is a match that should be highlighted.
+fn search_snippet(text: &str, query: &str) -> Vec<(String, bool)> {
+    let query_terms = tokenize(query);
+    let tokens = tokenize_with_offsets(text);
+    let first = tokens
+        .iter()
+        .position(|(_, _, t)| query_terms.contains(t))
+        .unwrap_or(0);
+    let first_start = tokens.get(first).map(|x| x.0).unwrap_or(0);
+    let start = tokens[..first]
+        .iter()
+        .rev()
+        .take_while(|x| first_start - x.0 < SEARCH_SNIPPET_LENGTH / 3)
+        .last()
+        .map(|x| x.0)
+        .unwrap_or(first_start);
+    let end = tokens
+        .iter()
+        .skip(first)
+        .take_while(|x| x.1 - start <= SEARCH_SNIPPET_LENGTH)
+        .last()
+        .map(|x| x.1)
+        .unwrap_or(text.len().min(start));
+
+    let mut out = Vec::new();
+    if start > 0 {
+        out.push(("…".to_string(), false));
+    }
+    let mut caret = start;
+    for (st, en, t) in tokens.iter().filter(|x| x.0 >= start && x.1 <= end) {
+        if query_terms.contains(t) {
+            out.push((text[caret..*st].to_string(), false));
+            out.push((text[*st..*en].to_string(), true));
+            caret = *en;
+        }
+    }
+    out.push((text[caret..end].to_string(), false));
+    if end < text.len() {
+        out.push(("…".to_string(), false));
+    }
+    out
+}
+
+fn render_search(state: &SharedState, query: &str) -> Markup {
+    let base_path = state.base_path.as_str();
+    let results = search_documents(&state.search_index, query);
+    html! {
         (DOCTYPE)
         html lang="en" {
             head {
-                title { "404 - Not Found" }
-                (pre_render_head())
+                title { "Search - Ben's Blog" }
+                meta name="robots" content="noindex";
+                (state.head)
             }
             body {
                 div.container {
                     header.row {
                         section class="column" {
-                            h1 { "Not found" }
+                            h1 { "Search" }
                         }
                         section class="column" {
-                            a href="/" {
+                            a href={ (base_path) "/" } {
                                 "All Posts"
                             }
+                            (pre_render_search_form(query, base_path))
                         }
                     }
                     main.row {
                         section.column {
-                            p {
-                                "Looks like the incoming link is wrong, corrupted, or the post has been removed. Sorry about that."
+                            @if query.trim().is_empty() {
+                                p { "Enter some words to search for in the posts on this blog." }
+                            } @else if results.is_empty() {
+                                p { "No posts matched \"" (query) "\"." }
+                            } @else {
+                                p { (results.len()) " posts matched \"" (query) "\"." }
+                                ul.index-nav-ul {
+                                    @for x in results.iter() {
+                                        li {
+                                            p {
+                                                a href={ (base_path) "/" (x.path) "/" } {
+                                                    time datetime=(x.date.format(&RFC3339_DATE_FORMAT).unwrap().to_string()) { (x.date.format(&POST_DATE_FORMAT).unwrap().to_string()) }
+                                                    (": ")
+                                                    @for (segment, matched) in search_snippet(&x.title, query) {
+                                                        @if matched { mark { (segment) } } @else { (segment) }
+                                                    }
+                                                }
+                                                br;
+                                                small {
+                                                    @for (segment, matched) in search_snippet(&x.text, query) {
+                                                        @if matched { mark { (segment) } } @else { (segment) }
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
                             }
                         }
                     }
-                    (pre_render_footer())
+                    (state.footer)
                 }
             }
         }
-    };
-    Cow::from(tree.into_string().as_bytes().to_owned()).to_owned()
+    }
 }
 
-fn make_hash_of_bytes(x: Cow<'static, [u8]>) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    hasher.write(CRATE_VERSION.as_bytes());
-    hasher.write(x.as_ref());
-    hasher.finish()
-}
+/// Render the 1200x630 png preview card used as the social image of a post which doesn't declare or embed its own.
+fn pre_render_social_card(title: &str, date: &PrimitiveDateTime) -> Cow<'static, [u8]> {
+    use ab_glyph::{Font, FontRef, PxScale, ScaleFont};
 
-fn make_hash(x: &str, y: &str) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    hasher.write(CRATE_VERSION.as_bytes());
-    hasher.write(x.as_bytes());
-    hasher.write(y.as_bytes());
-    hasher.finish()
-}
+    const BACKGROUND: [u8; 3] = [0xfd, 0xfa, 0xe9];
+    const FOREGROUND: [u8; 3] = [0x39, 0x42, 0x40];
+    const ACCENT: [u8; 3] = [0x9b, 0x4d, 0xca];
+    const MARGIN: f32 = 80.0;
 
-fn check_etag_and_return(
-    etag: String,
-    req_headers: &HeaderMap,
-    resp_headers: &HeaderMap,
-) -> Option<Response> {
-    if req_headers
-        .get(http::header::IF_NONE_MATCH)
-        .map(|v| v.to_str().unwrap().eq(etag.as_str()))
-        .unwrap_or(false)
-    {
-        return Some((StatusCode::NOT_MODIFIED, resp_headers.clone()).into_response());
+    let font_data = Asset::get(SOCIAL_CARD_FONT).unwrap().data;
+    let font = FontRef::try_from_slice(font_data.as_ref()).unwrap();
+    let (width, height) = (SOCIAL_CARD_WIDTH as usize, SOCIAL_CARD_HEIGHT as usize);
+
+    let mut pixels: Vec<u8> = BACKGROUND.repeat(width * height);
+    for y in 0..16 {
+        for x in 0..width {
+            pixels[(y * width + x) * 3..(y * width + x) * 3 + 3].copy_from_slice(&ACCENT);
+        }
     }
-    None
-}
 
-fn gen_not_found(state: State<Arc<SharedState>>, req_headers: HeaderMap) -> Response {
-    let provide_html = req_headers
-        .get("accept")
-        .map(|v| v.to_str().unwrap().contains("text/html"))
-        .unwrap_or(false);
+    let text_width = |text: &str, scale: PxScale| -> f32 {
+        let scaled = font.as_scaled(scale);
+        let mut last = None;
+        let mut w = 0.0;
+        for c in text.chars() {
+            let id = scaled.glyph_id(c);
+            if let Some(l) = last {
+                w += scaled.kern(l, id);
+            }
+            w += scaled.h_advance(id);
+            last = Some(id);
+        }
+        w
+    };
 
-    if provide_html {
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            http::header::CONTENT_TYPE,
-            state.not_found.content_type.clone(),
-        );
-        headers.insert(
-            http::header::CACHE_CONTROL,
-            HeaderValue::from_str(CACHE_CONTROL).unwrap(),
-        );
-        (
-            StatusCode::NOT_FOUND,
-            headers,
-            state.not_found.content.clone(),
-        )
-            .into_response()
-    } else {
-        StatusCode::NOT_FOUND.into_response()
+    let mut draw_text = |text: &str, scale: PxScale, left: f32, baseline: f32, color: [u8; 3]| {
+        let scaled = font.as_scaled(scale);
+        let mut caret = left;
+        let mut last = None;
+        for c in text.chars() {
+            let id = scaled.glyph_id(c);
+            if let Some(l) = last {
+                caret += scaled.kern(l, id);
+            }
+            let glyph = id.with_scale_and_position(scale, ab_glyph::point(caret, baseline));
+            caret += scaled.h_advance(id);
+            last = Some(id);
+            if let Some(outlined) = font.outline_glyph(glyph) {
+                let bounds = outlined.px_bounds();
+                outlined.draw(|gx, gy, coverage| {
+                    let x = bounds.min.x as i64 + gx as i64;
+                    let y = bounds.min.y as i64 + gy as i64;
+                    if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+                        return;
+                    }
+                    let offset = (y as usize * width + x as usize) * 3;
+                    for i in 0..3 {
+                        let bg = pixels[offset + i] as f32;
+                        pixels[offset + i] = (bg + (color[i] as f32 - bg) * coverage) as u8;
+                    }
+                });
+            }
+        }
+    };
+
+    // wrap the title onto at most 3 lines, truncating the last one when the title is too long
+    let title_scale = PxScale::from(72.0);
+    let max_width = SOCIAL_CARD_WIDTH as f32 - 2.0 * MARGIN;
+    let mut lines: Vec<String> = vec![];
+    for word in title.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if text_width(&format!("{} {}", line, word), title_scale) <= max_width => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_string()),
+        }
+    }
+    if lines.len() > 3 {
+        lines.truncate(3);
+        lines[2].push('…');
+    }
+
+    let mut baseline = MARGIN + 96.0;
+    for line in lines.iter() {
+        draw_text(line, title_scale, MARGIN, baseline, FOREGROUND);
+        baseline += 88.0;
+    }
+    draw_text(
+        &date.format(&POST_DATE_FORMAT).unwrap(),
+        PxScale::from(36.0),
+        MARGIN,
+        baseline + 24.0,
+        ACCENT,
+    );
+    draw_text(
+        "Ben's Blog",
+        PxScale::from(36.0),
+        MARGIN,
+        SOCIAL_CARD_HEIGHT as f32 - MARGIN,
+        FOREGROUND,
+    );
+
+    let mut out = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut out, SOCIAL_CARD_WIDTH, SOCIAL_CARD_HEIGHT);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&pixels).unwrap();
     }
+    Cow::from(out)
 }
 
-async fn not_found(state: State<Arc<SharedState>>, headers: HeaderMap) -> Response {
-    gen_not_found(state, headers)
+/// Build the html page served with an error status.
+fn build_error_item(status: StatusCode, base_path: &str) -> Cow<'static, Item> {
+    let content = pre_render_error_page(status, base_path);
+    Cow::Owned(Item {
+        content: content.clone(),
+        encoded: encode_variants(
+            &format!("{} page", status),
+            HTML_CONTENT_TYPE,
+            content.as_ref(),
+        ),
+        content_type: HeaderValue::from_str(HTML_CONTENT_TYPE).unwrap(),
+        etag: make_etag(content.as_ref()),
+        last_modified: None,
+        script_hashes: vec![],
+        image_variants: vec![],
+        cache_class: CacheClass::Error,
+        children: HashMap::new(),
+    })
 }
 
-async fn view_root_item(
-    state: State<Arc<SharedState>>,
-    req_headers: HeaderMap,
-    req: Request<axum::body::Body>,
-) -> Response {
-    view_nested_item(
-        Path(("".to_string(), "".to_string())),
-        state,
-        req_headers,
-        req,
+fn pre_render_error_page(status: StatusCode, base_path: &str) -> Cow<'static, [u8]> {
+    let (heading, message) = match status {
+        StatusCode::GONE => (
+            "Gone",
+            "This post has been removed on purpose and won't be coming back. Sorry about that.",
+        ),
+        _ => (
+            "Not found",
+            "Looks like the incoming link is wrong, corrupted, or the post has been removed. Sorry about that.",
+        ),
+    };
+    let tree = html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                title { (status.as_u16()) " - " (status.canonical_reason().unwrap_or(heading)) }
+                (pre_render_head(base_path))
+            }
+            body {
+                div.container {
+                    header.row {
+                        section class="column" {
+                            h1 { (heading) }
+                        }
+                        section class="column" {
+                            a href={ (base_path) "/" } {
+                                "All Posts"
+                            }
+                            (pre_render_search_form("", base_path))
+                        }
+                    }
+                    main.row {
+                        section.column {
+                            p { (message) }
+                        }
+                    }
+                    (pre_render_footer())
+                }
+            }
+        }
+    };
+    Cow::from(tree.into_string().as_bytes().to_owned())
+}
+
+/// Build the CSP source expression which allows the given inline script content to be loaded.
+fn make_csp_hash(script: &str) -> String {
+    use base64::Engine;
+    use sha2::Digest;
+    let digest = sha2::Sha256::digest(script.as_bytes());
+    format!(
+        "'sha256-{}'",
+        base64::engine::general_purpose::STANDARD.encode(digest)
     )
-    .await
 }
 
-async fn view_item(
-    Path(key): Path<String>,
-    state: State<Arc<SharedState>>,
-    req_headers: HeaderMap,
-    req: Request<axum::body::Body>,
-) -> Response {
-    view_nested_item(Path((key, "".to_string())), state, req_headers, req).await
+/// Serialize a JSON-LD document so that it is safe to embed inside a script element.
+fn to_json_ld_script(value: &serde_json::Value) -> String {
+    value.to_string().replace("</", "<\\/")
 }
 
-async fn view_nested_item(
-    Path(key): Path<(String, String)>,
-    state: State<Arc<SharedState>>,
-    req_headers: HeaderMap,
-    req: Request<axum::body::Body>,
-) -> Response {
-    let mut x = state.root.clone();
-    if !key.0.is_empty() {
-        if let Some(y) = x.children.get(key.0.as_str()) {
-            x = y.clone();
-            if !key.1.is_empty() {
-                if let Some(z) = x.children.get(key.1.as_str()) {
-                    x = z.clone()
-                } else {
-                    return gen_not_found(state, req_headers);
-                }
-            }
+fn json_ld_author(external_url_prefix: &String) -> serde_json::Value {
+    serde_json::json!({
+        "@type": "Person",
+        "name": "Ben Meier",
+        "url": format!("{}/", external_url_prefix),
+        "sameAs": ["https://hachyderm.io/@benmeier_", "https://github.com/astromechza"],
+    })
+}
 
-            // if we are loading a root item that doesn't end in slash and is html, lets redirect to the slash path.
-            if !req.uri().path().ends_with('/')
-                && x.content_type
-                    .to_str()
-                    .map(|s| s.eq(HTML_CONTENT_TYPE))
-                    .unwrap_or_default()
-            {
-                let mut headers = HeaderMap::new();
-                let mut newpath = req.uri().path().to_owned();
-                newpath.push('/');
-                headers.insert(
-                    http::header::LOCATION,
-                    HeaderValue::from_str(newpath.as_str()).unwrap(),
-                );
-                headers.insert(
-                    http::header::CACHE_CONTROL,
-                    HeaderValue::from_str(CACHE_CONTROL).unwrap(),
-                );
-                return (StatusCode::TEMPORARY_REDIRECT, headers.clone()).into_response();
-            }
-        } else {
-            return gen_not_found(state, req_headers);
+fn render_post_json_ld(post: &Post, external_url_prefix: &String) -> String {
+    let url = format!("{}/{}/", external_url_prefix, post.path);
+    let date = post.date.format(&RFC3339_DATE_FORMAT).unwrap();
+    to_json_ld_script(&serde_json::json!({
+        "@context": "https://schema.org",
+        "@type": "BlogPosting",
+        "headline": post.title,
+        "description": post.description,
+        "url": url,
+        "mainEntityOfPage": post.canonical.clone().unwrap_or(url.clone()),
+        "datePublished": date,
+        "dateModified": date,
+        "author": json_ld_author(external_url_prefix),
+        "image": post.image.clone().unwrap_or(format!("{}{}", url, SOCIAL_CARD_FILE_NAME)),
+        "wordCount": post.word_count,
+        "keywords": post.tags,
+    }))
+}
+
+fn render_index_json_ld(posts: &[Post], external_url_prefix: &String) -> String {
+    to_json_ld_script(&serde_json::json!({
+        "@context": "https://schema.org",
+        "@type": "Blog",
+        "name": "Ben's Blog",
+        "description": "Technical blog of Ben Meier",
+        "url": format!("{}/", external_url_prefix),
+        "image": format!("{}/url-image.jpg", external_url_prefix),
+        "author": json_ld_author(external_url_prefix),
+        "blogPost": posts.iter().map(|x| serde_json::json!({
+            "@type": "BlogPosting",
+            "headline": x.title,
+            "url": format!("{}/{}/", external_url_prefix, x.path),
+            "datePublished": x.date.format(&RFC3339_DATE_FORMAT).unwrap(),
+        })).collect::<Vec<serde_json::Value>>(),
+    }))
+}
+
+/// Whether content of the given media type is worth compressing. Media formats that carry their own compression gain
+/// next to nothing and would only double the memory they take up, with svg the exception as it is plain xml.
+fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match essence.split_once('/') {
+        Some(("text", _)) => true,
+        Some(("image", subtype)) => subtype == "svg+xml" || subtype == "bmp" || subtype == "x-icon",
+        Some(("application", subtype)) => {
+            subtype.ends_with("+xml")
+                || subtype.ends_with("+json")
+                || ["json", "xml", "javascript", "wasm"].contains(&subtype)
         }
+        Some(("font", subtype)) => subtype == "ttf" || subtype == "otf",
+        _ => false,
     }
+}
 
-    let mut headers = HeaderMap::new();
-    headers.insert(http::header::CONTENT_TYPE, x.content_type.clone());
-    headers.insert(
-        http::header::ETAG,
-        HeaderValue::from_str(x.etag.as_str()).unwrap(),
-    );
-    headers.insert(
-        http::header::CACHE_CONTROL,
-        HeaderValue::from_str(CACHE_CONTROL).unwrap(),
+/// Precompute the compressed variants of the content that are worth keeping in memory, logging how much was saved.
+fn encode_variants(
+    name: &str,
+    content_type: &str,
+    content: &[u8],
+) -> Vec<(ContentEncoding, Bytes)> {
+    use std::io::Write;
+
+    if !is_compressible(content_type) {
+        tracing::info!(
+            "{}: {} bytes of {} left uncompressed",
+            name,
+            content.len(),
+            content_type
+        );
+        return vec![];
+    }
+
+    let mut brotli_out = Vec::new();
+    {
+        let mut w = brotli::CompressorWriter::new(&mut brotli_out, 4096, 9, 22);
+        w.write_all(content).unwrap();
+    }
+
+    let zstd_out = zstd::bulk::compress(content, 12).unwrap();
+
+    let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gzip.write_all(content).unwrap();
+    let gzip_out = gzip.finish().unwrap();
+
+    let mut variants = vec![];
+    let mut summary = vec![];
+    let mut dropped_bytes = 0;
+    for (encoding, out) in [
+        (ContentEncoding::Brotli, brotli_out),
+        (ContentEncoding::Zstd, zstd_out),
+        (ContentEncoding::Gzip, gzip_out),
+    ] {
+        // a variant has to save a meaningful share of the bytes to be worth the memory and the cpu to decode it
+        if out.len() * 100 > content.len() * (100 - COMPRESSION_MIN_SAVING_PERCENT) {
+            summary.push(format!("{} dropped", encoding.as_str()));
+            dropped_bytes += out.len();
+            continue;
+        }
+        summary.push(format!("{} {} bytes", encoding.as_str(), out.len()));
+        variants.push((encoding, Bytes::from(out)));
+    }
+    tracing::info!(
+        "{}: {} bytes, {}, {} bytes of variants not kept",
+        name,
+        content.len(),
+        summary.join(", "),
+        dropped_bytes
     );
+    variants
+}
+
+/// Parse a header listing values with optional q-value weights, such as Accept or Accept-Encoding, into the values and
+/// their weights.
+fn parse_quality_list(header: &str) -> Vec<(&str, f32)> {
+    let mut preferences: Vec<(&str, f32)> = vec![];
+    for part in header.split(',') {
+        let mut params = part.split(';').map(|p| p.trim());
+        let value = params.next().unwrap_or_default();
+        if value.is_empty() {
+            continue;
+        }
+        let q = params
+            .filter_map(|p| p.strip_prefix("q=").or(p.strip_prefix("Q=")))
+            .filter_map(|p| p.parse::<f32>().ok())
+            .next_back()
+            .unwrap_or(1.0);
+        preferences.push((value, q));
+    }
+    preferences
+}
+
+/// Pick the image variant of an item to respond with given the Accept request header, or None to send the original.
+/// A variant has to be named explicitly rather than through a wildcard so that clients which accept anything, but may
+/// not understand the newer formats, keep getting the original.
+fn negotiate_image_variant<'a>(accept: Option<&HeaderValue>, item: &'a Item) -> Option<&'a Item> {
+    let header = accept.and_then(|v| v.to_str().ok())?;
+    let preferences = parse_quality_list(header);
+    let exact = |media_type: &str| {
+        preferences
+            .iter()
+            .find(|(m, _)| m.eq_ignore_ascii_case(media_type))
+            .map(|(_, q)| *q)
+    };
+    let original_type = item.content_type.to_str().unwrap_or_default();
+    let original_q = exact(original_type)
+        .or_else(|| {
+            let wildcard = format!("{}/*", original_type.split('/').next().unwrap_or_default());
+            exact(&wildcard)
+        })
+        .or_else(|| exact("*/*"))
+        .unwrap_or(0.0);
+    item.image_variants.iter().find(|v| {
+        exact(v.content_type.to_str().unwrap_or_default())
+            .map(|q| q > 0.0 && q >= original_q)
+            .unwrap_or(false)
+    })
+}
+
+/// Pick the content coding to respond with given the Accept-Encoding request header and the codings available, using
+/// the q-values as described in RFC 9110 section 12.5.3. Returns None when not even identity is acceptable.
+fn negotiate_encoding(
+    accept_encoding: Option<&HeaderValue>,
+    available: &[ContentEncoding],
+) -> Option<ContentEncoding> {
+    let header = match accept_encoding.and_then(|v| v.to_str().ok()) {
+        Some(h) => h,
+        // no preference expressed so stick with the original representation
+        None => return Some(ContentEncoding::Identity),
+    };
+    let preferences = parse_quality_list(header);
+    let weight = |encoding: &ContentEncoding| -> f32 {
+        if let Some((_, q)) = preferences.iter().find(|(c, _)| encoding.matches(c)) {
+            return *q;
+        }
+        if let Some((_, q)) = preferences.iter().find(|(c, _)| *c == "*") {
+            return *q;
+        }
+        // identity is acceptable unless explicitly excluded but only as a last resort, everything else must be asked for
+        if *encoding == ContentEncoding::Identity {
+            f32::MIN_POSITIVE
+        } else {
+            0.0
+        }
+    };
+    let mut best: Option<(ContentEncoding, f32)> = None;
+    for encoding in ContentEncoding::PREFERENCE.iter() {
+        if *encoding != ContentEncoding::Identity && !available.contains(encoding) {
+            continue;
+        }
+        let q = weight(encoding);
+        if q > 0.0 && best.map(|(_, bq)| q > bq).unwrap_or(true) {
+            best = Some((*encoding, q));
+        }
+    }
+    best.map(|(e, _)| e)
+}
+
+/// Add the framing, content security, referrer and sniffing headers of a page, allowing the given inline script
+/// hashes to run.
+fn insert_security_headers(headers: &mut HeaderMap, script_hashes: &[String]) {
     headers.insert(
         http::header::X_FRAME_OPTIONS,
         HeaderValue::from_str("DENY").unwrap(),
     );
+    let mut csp = CONTENT_SECURITY_POLICY.to_string();
+    if !script_hashes.is_empty() {
+        csp.push_str("; script-src ");
+        csp.push_str(script_hashes.join(" ").as_str());
+    }
     headers.insert(
         http::header::CONTENT_SECURITY_POLICY,
-        HeaderValue::from_str(
-            "default-src 'none'; style-src 'nonce-123456789'; img-src 'self' data: https:",
-        )
-        .unwrap(),
+        HeaderValue::from_str(csp.as_str()).unwrap(),
     );
     headers.insert(
         http::header::REFERRER_POLICY,
@@ -694,453 +2742,4106 @@ async fn view_nested_item(
         http::header::X_CONTENT_TYPE_OPTIONS,
         HeaderValue::from_str("nosniff").unwrap(),
     );
+}
 
-    if let Some(not_modified) = check_etag_and_return(x.etag.clone(), &req_headers, &headers) {
-        return not_modified;
+/// Add the headers of a cache policy to a response.
+fn insert_cache_headers(headers: &mut HeaderMap, policy: &CachePolicy) {
+    headers.insert(
+        http::header::CACHE_CONTROL,
+        HeaderValue::from_str(policy.cache_control.as_str()).unwrap(),
+    );
+    if let Some(cdn) = &policy.cdn_cache_control {
+        headers.insert(
+            HeaderName::from_static("cdn-cache-control"),
+            HeaderValue::from_str(cdn.as_str()).unwrap(),
+        );
     }
-
-    let ae_header = req_headers.get(http::header::ACCEPT_ENCODING);
-    if ae_header.is_some() && ae_header.unwrap().to_str().unwrap().contains("deflate") {
+    if let Some(cloudflare) = &policy.cloudflare_cdn_cache_control {
         headers.insert(
-            http::header::CONTENT_ENCODING,
-            HeaderValue::from_str("deflate").unwrap(),
+            HeaderName::from_static("cloudflare-cdn-cache-control"),
+            HeaderValue::from_str(cloudflare.as_str()).unwrap(),
         );
-        return (StatusCode::OK, headers, x.compressed.clone()).into_response();
     }
-
-    (StatusCode::OK, headers, x.content.clone()).into_response()
 }
 
-async fn healthcheck() -> Response {
-    (StatusCode::NO_CONTENT).into_response()
+/// The entity tag of some content, derived from a sha256 of the bytes so that it changes whenever they do and is the
+/// same on every replica.
+fn make_etag(content: &[u8]) -> String {
+    use sha2::Digest;
+    let digest = sha2::Sha256::digest(content);
+    digest[..16].iter().map(|b| format!("{:02x}", b)).collect()
 }
 
-#[derive(Default, Clone)]
-struct HttpTraceLayerHooks;
+/// The quoted strong entity tag of one encoding of an item, as each variant is a different representation with its
+/// own bytes.
+fn variant_etag(etag: &str, encoding: ContentEncoding) -> String {
+    match encoding {
+        ContentEncoding::Identity => format!("\"{}\"", etag),
+        _ => format!("\"{}-{}\"", etag, encoding.as_str()),
+    }
+}
 
-impl<B> MakeSpan<B> for HttpTraceLayerHooks {
-    fn make_span(&mut self, req: &Request<B>) -> Span {
-        // see https://github.com/open-telemetry/semantic-conventions/blob/main/docs/http/http-spans.md
-        let span = tracing::info_span!(
-            // span name here will be ignored by open telemetry and replaced with otel.name
-            "request",
-            // the span name
-            otel.name = tracing::field::Empty,
-            otel.kind = "server",
-            otel.status_code = tracing::field::Empty,
-            // common attributes
-            http.response.status_code = tracing::field::Empty,
-            http.request.body.size = tracing::field::Empty,
-            http.response.body.size = tracing::field::Empty,
-            http.request.method = req.method().as_str(),
-            network.protocol.name = "http",
-            network.protocol.version = format!("{:?}", req.version()).strip_prefix("HTTP/"),
-            user_agent.original = tracing::field::Empty,
-            // http request and response headers
-            http.request.header.x_forwarded_for = tracing::field::Empty,
-            http.request.header.cf_ipcountry = tracing::field::Empty,
-            http.request.header.referer = tracing::field::Empty,
-            // http server
-            http.route = tracing::field::Empty,
-            server.address = tracing::field::Empty,
-            server.port = tracing::field::Empty,
-            url.path = req.uri().path(),
-            url.query = tracing::field::Empty,
-            url.scheme = tracing::field::Empty,
-            // set on the failure hook
-            "error.type" = tracing::field::Empty,
-            error = tracing::field::Empty,
-        );
+/// Whether the entity tag list of an If-Match or If-None-Match header matches the quoted strong etag of the selected
+/// representation. Weak comparison ignores the W/ prefix while strong comparison never matches a weak tag, see RFC 9110
+/// section 8.8.3.2. Anything unparseable ends the list rather than failing the request.
+fn entity_tag_list_matches(header: &HeaderValue, etag: &str, weak_comparison: bool) -> bool {
+    let value = header.as_bytes();
+    if value.trim_ascii() == b"*" {
+        return true;
+    }
+    let mut rest = value;
+    loop {
+        rest = rest.trim_ascii_start();
+        while let Some(r) = rest.strip_prefix(b",") {
+            rest = r.trim_ascii_start();
+        }
+        if rest.is_empty() {
+            return false;
+        }
+        let weak = rest.starts_with(b"W/");
+        if weak {
+            rest = &rest[2..];
+        }
+        if !rest.starts_with(b"\"") {
+            return false;
+        }
+        let end = match rest[1..].iter().position(|b| *b == b'"') {
+            Some(end) => end + 2,
+            None => return false,
+        };
+        if &rest[..end] == etag.as_bytes() && (weak_comparison || !weak) {
+            return true;
+        }
+        rest = &rest[end..];
+    }
+}
 
-        req.uri().query().map(|v| span.record("url.query", v));
-        req.uri()
-            .scheme()
-            .map(|v| span.record("url.scheme", v.as_str()));
-        req.uri().host().map(|v| span.record("server.address", v));
-        req.uri().port_u16().map(|v| span.record("server.port", v));
+/// Parse an HTTP-date, accepting the preferred IMF-fixdate and the obsolete RFC 850 and asctime formats.
+fn parse_http_date(header: &HeaderValue) -> Option<PrimitiveDateTime> {
+    let value = header.to_str().ok()?.trim();
+    PrimitiveDateTime::parse(value, &HTTP_DATE_FORMAT)
+        .or_else(|_| PrimitiveDateTime::parse(value, &ASCTIME_DATE_FORMAT))
+        .ok()
+        .or_else(|| parse_rfc850_date(value))
+}
 
-        req.headers()
-            .get(http::header::CONTENT_LENGTH)
-            .map(|v| v.to_str().map(|v| span.record("http.request.body.size", v)));
-        req.headers()
-            .get(http::header::USER_AGENT)
-            .map(|v| v.to_str().map(|v| span.record("user_agent.original", v)));
-        req.headers().get("X-Forwarded-For").map(|v| {
-            v.to_str()
-                .map(|v| span.record("http.request.header.x_forwarded_for", v))
-        });
-        req.headers().get("CF-IPCountry").map(|v| {
-            v.to_str()
-                .map(|v| span.record("http.request.header.cf_ipcountry", v))
-        });
-        req.headers().get("Referer").map(|v| {
-            v.to_str()
-                .map(|v| span.record("http.request.header.referer", v))
-        });
+/// Parse an RFC 850 date, whose two digit year is taken as the most recent year with those digits that isn't more
+/// than 50 years in the future (RFC 9110 section 5.6.7).
+fn parse_rfc850_date(value: &str) -> Option<PrimitiveDateTime> {
+    let mut parsed = time::parsing::Parsed::new();
+    if !parsed
+        .parse_items(value.as_bytes(), RFC850_DATE_FORMAT)
+        .ok()?
+        .is_empty()
+    {
+        return None;
+    }
+    let now = OffsetDateTime::now_utc().year();
+    let mut year = now - now % 100 + i32::from(parsed.year_last_two()?);
+    if year > now + 50 {
+        year -= 100;
+    }
+    parsed.set_year(year)?;
+    PrimitiveDateTime::try_from(parsed).ok()
+}
 
-        if let Some(path) = req.extensions().get::<axum::extract::MatchedPath>() {
-            span.record("otel.name", format!("{} {}", req.method(), path.as_str()));
-            span.record("http.route", path.as_str());
-        } else {
-            span.record("otel.name", format!("{} -", req.method()));
-        };
+/// Evaluate the preconditions of a request in the order given by RFC 9110 section 13.2.2, returning the response to
+/// send instead of the content if one of them decides the request.
+fn evaluate_preconditions(
+    etag: &str,
+    last_modified: Option<PrimitiveDateTime>,
+    method: &http::Method,
+    req_headers: &HeaderMap,
+    resp_headers: &HeaderMap,
+) -> Option<Response> {
+    let safe = method == http::Method::GET || method == http::Method::HEAD;
 
-        span
+    if let Some(if_match) = req_headers.get(http::header::IF_MATCH) {
+        if !entity_tag_list_matches(if_match, etag, false) {
+            return Some(StatusCode::PRECONDITION_FAILED.into_response());
+        }
+    } else if let (Some(since), Some(lm)) = (
+        req_headers
+            .get(http::header::IF_UNMODIFIED_SINCE)
+            .and_then(parse_http_date),
+        last_modified,
+    ) {
+        if lm > since {
+            return Some(StatusCode::PRECONDITION_FAILED.into_response());
+        }
     }
-}
 
-impl<B> tower_http::trace::OnRequest<B> for HttpTraceLayerHooks {
-    fn on_request(&mut self, _: &Request<B>, _: &Span) {
-        tracing::event!(tracing::Level::DEBUG, "start processing request");
+    if let Some(if_none_match) = req_headers.get(http::header::IF_NONE_MATCH) {
+        if entity_tag_list_matches(if_none_match, etag, true) {
+            if safe {
+                return Some((StatusCode::NOT_MODIFIED, resp_headers.clone()).into_response());
+            }
+            return Some(StatusCode::PRECONDITION_FAILED.into_response());
+        }
+    } else if let (true, Some(since), Some(lm)) = (
+        safe,
+        req_headers
+            .get(http::header::IF_MODIFIED_SINCE)
+            .and_then(parse_http_date),
+        last_modified,
+    ) {
+        if lm <= since {
+            return Some((StatusCode::NOT_MODIFIED, resp_headers.clone()).into_response());
+        }
     }
+    None
 }
 
-impl<B> tower_http::trace::OnResponse<B> for HttpTraceLayerHooks {
-    fn on_response(self, response: &Response<B>, _: std::time::Duration, span: &Span) {
-        if let Some(size) = response.headers().get(http::header::CONTENT_LENGTH) {
-            span.record("http.response.body.size", size.to_str().unwrap());
+/// Parse a redirects file. Each line is an old path, then either a new path or url and an optional status of 301 (the
+/// default) or 308, or just 410 for content that was removed on purpose. Blank lines and lines starting with # are
+/// ignored.
+fn parse_redirects(source: &str) -> Result<Vec<Redirect>, String> {
+    let mut redirects = vec![];
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
-        span.record("http.response.status_code", response.status().as_u16());
-
-        // Server errors are handled by the OnFailure hook
-        if !response.status().is_server_error() {
-            tracing::event!(tracing::Level::INFO, "finished processing request");
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let redirect = match fields.as_slice() {
+            [from, "410"] => Redirect {
+                from: from.to_string(),
+                to: None,
+                status: StatusCode::GONE,
+            },
+            [from, to] | [from, to, "301"] => Redirect {
+                from: from.to_string(),
+                to: Some(to.to_string()),
+                status: StatusCode::MOVED_PERMANENTLY,
+            },
+            [from, to, "308"] => Redirect {
+                from: from.to_string(),
+                to: Some(to.to_string()),
+                status: StatusCode::PERMANENT_REDIRECT,
+            },
+            _ => return Err(format!("line {}: expected from, to and status", number + 1)),
+        };
+        if !redirect.from.starts_with('/') {
+            return Err(format!(
+                "line {}: {} is not a path",
+                number + 1,
+                redirect.from
+            ));
+        }
+        if redirect
+            .to
+            .as_ref()
+            .map(|to| to.contains('*') && !redirect.from.ends_with("/*"))
+            .unwrap_or_default()
+        {
+            return Err(format!(
+                "line {}: the target has a * but the path does not end in /*",
+                number + 1
+            ));
         }
+        redirects.push(redirect);
     }
+    Ok(redirects)
 }
 
-impl<FailureClass> tower_http::trace::OnFailure<FailureClass> for HttpTraceLayerHooks
-where
-    FailureClass: std::fmt::Display,
-{
-    fn on_failure(&mut self, error: FailureClass, _: std::time::Duration, _: &Span) {
-        tracing::event!(
-            tracing::Level::ERROR,
-            error = error.to_string(),
-            "error.type" = "_OTHER",
-            "response failed"
-        );
+/// Find the first redirect that matches the path, returning it with the location to send the client to. Trailing
+/// slashes don't matter for exact matches.
+fn match_redirect<'a>(
+    redirects: &'a [Redirect],
+    path: &str,
+) -> Option<(&'a Redirect, Option<String>)> {
+    let trimmed = path.trim_end_matches('/');
+    redirects.iter().find_map(|r| {
+        let rest = match r.from.strip_suffix("/*") {
+            Some(prefix) => match trimmed.strip_prefix(prefix) {
+                Some("") => "",
+                Some(rest) => rest.strip_prefix('/')?,
+                None => return None,
+            },
+            None if r.from.trim_end_matches('/') == trimmed => "",
+            None => return None,
+        };
+        // the remainder is copied into the target, so it must not be able to escape it
+        if rest.starts_with('/')
+            || rest.contains("//")
+            || rest.contains('\\')
+            || rest.contains("..")
+        {
+            return None;
+        }
+        let location = r.to.as_ref().map(|to| {
+            let mut location = to.replace('*', rest);
+            if path.ends_with('/') && to.ends_with('*') && !location.ends_with('/') {
+                location.push('/');
+            }
+            location
+        });
+        Some((r, location))
+    })
+}
+
+/// Find the item at a path relative to the root of the item tree.
+fn find_item<'a>(root: &'a Item, path: &str) -> Option<&'a Item> {
+    let mut x = root;
+    for k in path.split('/').filter(|k| !k.is_empty()) {
+        x = x.children.get(k)?;
     }
+    Some(x)
 }
 
-fn setup_router(external_url_prefix: String) -> Router {
-    let state = Arc::new(build_shared_state(
-        collect_posts(&external_url_prefix),
-        &external_url_prefix,
-    ));
-    let trace_layer = TraceLayer::new_for_http()
-        .make_span_with(HttpTraceLayerHooks::default())
-        .on_request(HttpTraceLayerHooks::default())
-        .on_response(HttpTraceLayerHooks::default())
-        .on_failure(HttpTraceLayerHooks::default());
-    Router::new()
-        .route("/", get(view_root_item))
-        .route("/livez", get(healthcheck))
-        .route("/readyz", get(healthcheck))
-        .route("/:a", get(view_item))
-        .route("/:a/", get(view_item))
-        .route("/:a/:b", get(view_nested_item))
-        .fallback(not_found)
-        .with_state(state)
-        .layer(trace_layer)
+/// Check that the base path and the name of every item are already in their normalised form, returning a description
+/// of each that isn't. Requests for any other form are redirected to the normalised one, so those would be unreachable.
+fn validate_paths(base_path: &str, root: &Item) -> Vec<String> {
+    let mut problems = vec![];
+    if normalize_path(base_path) != base_path
+        || base_path
+            .split('/')
+            .skip(1)
+            .any(|s| s.is_empty() || s == "." || s == "..")
+    {
+        problems.push(format!("base path {} is not a normalised path", base_path));
+    }
+    let mut pending = vec![(String::new(), root)];
+    while let Some((path, item)) = pending.pop() {
+        for (name, child) in item.children.iter() {
+            let child_path = format!("{}/{}", path, name);
+            if normalize_path(&child_path) != child_path {
+                problems.push(format!("{} is not a normalised path", child_path));
+            }
+            pending.push((child_path, child));
+        }
+    }
+    problems.sort();
+    problems
 }
 
-#[derive(Debug, Clone)]
-struct CustomSampler {}
+/// Check that the target of every redirect within the site exists, returning a description of each that doesn't.
+fn validate_redirects(root: &Item, redirects: &[Redirect]) -> Vec<String> {
+    let exists =
+        |path: &str| find_item(root, path.split(['?', '#']).next().unwrap_or_default()).is_some();
+    redirects
+        .iter()
+        .filter_map(|r| r.to.as_ref().map(|to| (r, to)))
+        // targets elsewhere can't be checked and wildcard targets depend on the request
+        .filter(|(_, to)| to.starts_with('/') && !to.contains('*'))
+        .filter(|(_, to)| !exists(to))
+        .map(|(r, to)| format!("{} redirects to {} which does not exist", r.from, to))
+        .collect()
+}
 
-impl ShouldSample for CustomSampler {
-    fn should_sample(
-        &self,
-        parent_context: Option<&Context>,
-        _trace_id: TraceId,
-        _name: &str,
-        _span_kind: &SpanKind,
-        attributes: &[KeyValue],
-        _links: &[Link],
-    ) -> SamplingResult {
-        let mut decision = SamplingDecision::RecordAndSample;
-        for a in attributes {
-            if a.key.as_str().eq("http.route")
-                && (a.value.as_str().eq("/livez") || a.value.as_str().eq("/readyz"))
-            {
-                decision = SamplingDecision::Drop;
-                break;
-            }
+/// Respond with the redirect or 410 for a path that isn't in the item tree, or the 404 when there is none.
+fn gen_redirect_or_not_found(
+    state: State<Arc<SharedState>>,
+    req_headers: HeaderMap,
+    uri: &http::Uri,
+) -> Response {
+    let (redirect, location) = match match_redirect(&state.redirects, uri.path()) {
+        Some(x) => x,
+        None => return gen_not_found(state, req_headers),
+    };
+    let location = match location {
+        Some(location) => location,
+        None => return gen_error(state.clone(), req_headers, redirect.status),
+    };
+    let mut headers = HeaderMap::new();
+    let location = match location.starts_with('/') {
+        true if is_same_site_location(&location) => format!("{}{}", state.base_path, location),
+        true => return gen_not_found(state, req_headers),
+        false => location,
+    };
+    let location = match uri.query() {
+        Some(query) if !location.contains('?') => format!("{}?{}", location, query),
+        _ => location,
+    };
+    match HeaderValue::from_str(location.as_str()) {
+        Ok(location) => headers.insert(http::header::LOCATION, location),
+        Err(_) => return gen_not_found(state, req_headers),
+    };
+    insert_cache_headers(&mut headers, state.cache_policy(CacheClass::Redirect));
+    (redirect.status, headers).into_response()
+}
+
+fn gen_not_found(state: State<Arc<SharedState>>, req_headers: HeaderMap) -> Response {
+    gen_error(state, req_headers, StatusCode::NOT_FOUND)
+}
+
+/// Respond with an error status, with the page for that status for clients that want html.
+fn gen_error(
+    state: State<Arc<SharedState>>,
+    req_headers: HeaderMap,
+    status: StatusCode,
+) -> Response {
+    let provide_html = req_headers
+        .get("accept")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.contains("text/html"))
+        .unwrap_or(false);
+
+    let mut headers = HeaderMap::new();
+    insert_cache_headers(&mut headers, state.cache_policy(CacheClass::Error));
+    let page = match status {
+        StatusCode::GONE => &state.gone,
+        _ => &state.not_found,
+    };
+    if provide_html {
+        headers.insert(http::header::CONTENT_TYPE, page.content_type.clone());
+        (status, headers, page.content.clone()).into_response()
+    } else {
+        (status, headers).into_response()
+    }
+}
+
+async fn not_found(state: State<Arc<SharedState>>, headers: HeaderMap, uri: http::Uri) -> Response {
+    gen_redirect_or_not_found(state, headers, &uri)
+}
+
+async fn search(
+    state: State<Arc<SharedState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let query = params.get("q").map(|q| q.as_str()).unwrap_or_default();
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_str(HTML_CONTENT_TYPE).unwrap(),
+    );
+    insert_cache_headers(&mut headers, state.cache_policy(CacheClass::Search));
+    insert_security_headers(&mut headers, &[]);
+    (
+        StatusCode::OK,
+        headers,
+        render_search(&state, query).into_string(),
+    )
+        .into_response()
+}
+
+async fn view_root_item(
+    state: State<Arc<SharedState>>,
+    req_headers: HeaderMap,
+    req: Request<axum::body::Body>,
+) -> Response {
+    view_item_at_path(&[], state, req_headers, req)
+}
+
+async fn view_item(
+    Path(key): Path<String>,
+    state: State<Arc<SharedState>>,
+    req_headers: HeaderMap,
+    req: Request<axum::body::Body>,
+) -> Response {
+    view_item_at_path(&[key], state, req_headers, req)
+}
+
+async fn view_nested_item(
+    Path(key): Path<(String, String)>,
+    state: State<Arc<SharedState>>,
+    req_headers: HeaderMap,
+    req: Request<axum::body::Body>,
+) -> Response {
+    view_item_at_path(&[key.0, key.1], state, req_headers, req)
+}
+
+async fn view_deep_item(
+    Path(key): Path<(String, String, String)>,
+    state: State<Arc<SharedState>>,
+    req_headers: HeaderMap,
+    req: Request<axum::body::Body>,
+) -> Response {
+    view_item_at_path(&[key.0, key.1, key.2], state, req_headers, req)
+}
+
+fn view_item_at_path(
+    keys: &[String],
+    state: State<Arc<SharedState>>,
+    req_headers: HeaderMap,
+    req: Request<axum::body::Body>,
+) -> Response {
+    let mut x: &Item = &state.root;
+    for k in keys.iter() {
+        if let Some(y) = x.children.get(k.as_str()) {
+            x = y;
+        } else {
+            return gen_redirect_or_not_found(state.clone(), req_headers, req.uri());
         }
-        SamplingResult {
-            decision,
-            // No extra attributes ever set by the SDK samplers.
-            attributes: Vec::new(),
-            // all sampler in SDK will not modify trace state.
-            trace_state: match parent_context {
-                Some(ctx) => ctx.span().span_context().trace_state().clone(),
-                None => TraceState::default(),
-            },
+    }
+
+    let is_html = x
+        .content_type
+        .to_str()
+        .map(|s| s.eq(HTML_CONTENT_TYPE))
+        .unwrap_or_default();
+    // if we are loading a nested item that doesn't end in slash and is html, lets redirect to the slash path.
+    if !keys.is_empty() && !req.uri().path().ends_with('/') && is_html {
+        let mut newpath = format!("{}{}", state.base_path, req.uri().path());
+        newpath.push('/');
+        return gen_canonical_redirect(&state, newpath, req.uri().query());
+    }
+    // only pages live at slash paths, so other items have a single url
+    if !keys.is_empty() && req.uri().path().ends_with('/') && !is_html {
+        return gen_not_found(state, req_headers);
+    }
+
+    // the cache policy stays that of the requested item whichever image variant is sent
+    let cache_class = x.cache_class;
+    let vary = if x.image_variants.is_empty() {
+        "Accept-Encoding"
+    } else {
+        "Accept-Encoding, Accept"
+    };
+    if let Some(variant) = negotiate_image_variant(req_headers.get(http::header::ACCEPT), x) {
+        x = variant;
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(http::header::CONTENT_TYPE, x.content_type.clone());
+    headers.insert(http::header::VARY, HeaderValue::from_static(vary));
+    insert_cache_headers(&mut headers, state.cache_policy(cache_class));
+    insert_security_headers(&mut headers, &x.script_hashes);
+    headers.insert(
+        http::header::ACCEPT_RANGES,
+        HeaderValue::from_static("bytes"),
+    );
+
+    let available: Vec<ContentEncoding> = x.encoded.iter().map(|(e, _)| *e).collect();
+    let encoding =
+        match negotiate_encoding(req_headers.get(http::header::ACCEPT_ENCODING), &available) {
+            Some(e) => e,
+            None => return (StatusCode::NOT_ACCEPTABLE, headers).into_response(),
+        };
+
+    let etag = variant_etag(&x.etag, encoding);
+    headers.insert(
+        http::header::ETAG,
+        HeaderValue::from_str(etag.as_str()).unwrap(),
+    );
+    if let Some(lm) = x.last_modified {
+        headers.insert(
+            http::header::LAST_MODIFIED,
+            HeaderValue::from_str(lm.format(&HTTP_DATE_FORMAT).unwrap().as_str()).unwrap(),
+        );
+    }
+    if let Some(resp) =
+        evaluate_preconditions(&etag, x.last_modified, req.method(), &req_headers, &headers)
+    {
+        return resp;
+    }
+
+    let body = match encoding {
+        ContentEncoding::Identity => match &x.content {
+            Cow::Borrowed(content) => Bytes::from_static(content),
+            Cow::Owned(content) => Bytes::copy_from_slice(content),
+        },
+        _ => {
+            headers.insert(
+                http::header::CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.as_str()),
+            );
+            x.encoded
+                .iter()
+                .find(|(e, _)| *e == encoding)
+                .map(|(_, b)| b.clone())
+                .unwrap()
         }
+    };
+
+    // ranges apply to the selected representation, so to the compressed bytes when there is a content encoding
+    let ranges = req_headers
+        .get(http::header::RANGE)
+        .filter(|_| {
+            req_headers
+                .get(http::header::IF_RANGE)
+                .map(|v| if_range_matches(v, &etag, x.last_modified))
+                .unwrap_or(true)
+        })
+        .and_then(|v| parse_range(v, body.len()));
+    match ranges {
+        Some(ranges) => partial_content(&ranges, &body, &x.etag, headers),
+        None => (StatusCode::OK, headers, body).into_response(),
     }
 }
 
-#[tokio::main]
-async fn main() {
-    let mut args = Cli::parse();
-    if args.external_url_prefix.is_none() {
-        args.external_url_prefix = match std::env::var("EXTERNAL_URL_PREFIX") {
-            Ok(v) => Some(v),
-            Err(_) => None,
+/// Parse a Range header against a representation of the given length, see RFC 9110 section 14.1.2. Returns None when
+/// the header has to be ignored and an empty list when none of the ranges can be satisfied. Overlapping ranges are
+/// coalesced so that a request can't ask for more than the whole content.
+fn parse_range(header: &HeaderValue, length: usize) -> Option<Vec<std::ops::Range<usize>>> {
+    let value = header.to_str().ok()?.trim();
+    let (unit, specs) = value.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let mut ranges: Vec<std::ops::Range<usize>> = vec![];
+    let mut count = 0;
+    for spec in specs.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        count += 1;
+        if count > RANGE_MAX_COUNT {
+            return None;
+        }
+        let (first, last) = spec.split_once('-')?;
+        if first.is_empty() {
+            let suffix: usize = last.parse().ok()?;
+            if suffix > 0 && length > 0 {
+                ranges.push(length - suffix.min(length)..length);
+            }
+            continue;
+        }
+        let first: usize = first.parse().ok()?;
+        let last: Option<usize> = if last.is_empty() {
+            None
+        } else {
+            Some(last.parse().ok()?)
+        };
+        if last.map(|l| l < first).unwrap_or(false) {
+            return None;
+        }
+        if first < length {
+            let end = last
+                .map(|l| l.saturating_add(1).min(length))
+                .unwrap_or(length);
+            ranges.push(first..end);
         }
     }
-    let honeycomb_key_path =
-        std::env::var("HONEYCOMB_KEY_PATH").unwrap_or_else(|_| "honeycomb.key".to_string());
-    match std::fs::read_to_string(honeycomb_key_path) {
-        Ok(api_key) if !api_key.trim().is_empty() => {
-            let otlp_endpoint = "https://api.honeycomb.io";
-            let otlp_headers =
-                HashMap::from([("x-honeycomb-team".into(), api_key.trim().to_string())]);
-            let otlp_service_name = args
-                .external_url_prefix
-                .clone()
-                .unwrap_or("unknown".to_string())
-                .replace("://", "-")
-                .replace(&['.', '/'], "-");
+    if count == 0 {
+        return None;
+    }
 
-            let tracer = opentelemetry_otlp::new_pipeline()
-                .tracing()
-                .with_exporter(
-                    opentelemetry_otlp::new_exporter()
-                        .http()
-                        .with_endpoint(otlp_endpoint)
-                        .with_http_client(reqwest::Client::default())
-                        .with_headers(otlp_headers)
-                        .with_timeout(std::time::Duration::from_secs(5)),
-                ) // Replace with runtime::Tokio if using async main
-                .with_trace_config(
-                    opentelemetry_sdk::trace::config()
-                        .with_sampler(CustomSampler {})
-                        .with_resource(opentelemetry_sdk::Resource::new(vec![
-                            opentelemetry::KeyValue::new("service.name", otlp_service_name),
-                        ])),
-                )
-                .install_batch(opentelemetry_sdk::runtime::Tokio)
-                .unwrap();
+    ranges.sort_by_key(|r| r.start);
+    let mut coalesced: Vec<std::ops::Range<usize>> = vec![];
+    for r in ranges {
+        match coalesced.last_mut() {
+            Some(previous) if r.start <= previous.end => previous.end = previous.end.max(r.end),
+            _ => coalesced.push(r),
+        }
+    }
+    Some(coalesced)
+}
 
-            let subscriber = tracing_subscriber::registry::Registry::default()
-                .with(tracing_subscriber::filter::LevelFilter::INFO) // filter out low-level debug tracing (eg tokio executor)
-                .with(tracing_subscriber::fmt::Layer::default()) // log to stdout
-                .with(tracing_opentelemetry::layer().with_tracer(tracer)); // traces can go to open telemetry
-            tracing::subscriber::set_global_default(subscriber)
-                .expect("failed to set tracing subscriber");
-            tracing::info!("set up honeycomb tracing");
+/// Whether an If-Range header lets the range request through, which needs the representation to be unchanged by a
+/// strong comparison of the entity tag or an exact match of the modification date.
+fn if_range_matches(
+    header: &HeaderValue,
+    etag: &str,
+    last_modified: Option<PrimitiveDateTime>,
+) -> bool {
+    let value = header.as_bytes().trim_ascii();
+    if value.starts_with(b"\"") || value.starts_with(b"W/") {
+        return value == etag.as_bytes();
+    }
+    match (parse_http_date(header), last_modified) {
+        (Some(date), Some(lm)) => date == lm,
+        _ => false,
+    }
+}
+
+/// Pick a multipart boundary from the seed which doesn't appear in any of the parts, as RFC 2046 section 5.1.1
+/// requires.
+fn multipart_boundary(seed: &str, parts: &[&[u8]]) -> String {
+    let mut boundary = seed.to_string();
+    let mut attempt = 0;
+    while parts
+        .iter()
+        .any(|p| p.windows(boundary.len()).any(|w| w == boundary.as_bytes()))
+    {
+        attempt += 1;
+        boundary = format!("{}-{}", seed, attempt);
+    }
+    boundary
+}
+
+/// Build a 206 response with the requested ranges of the body, as multipart/byteranges when there is more than one, or
+/// a 416 when there are none.
+fn partial_content(
+    ranges: &[std::ops::Range<usize>],
+    body: &Bytes,
+    boundary_seed: &str,
+    mut headers: HeaderMap,
+) -> Response {
+    let content_range =
+        |r: &std::ops::Range<usize>| format!("bytes {}-{}/{}", r.start, r.end - 1, body.len());
+    match ranges {
+        [] => {
+            headers.remove(http::header::CONTENT_TYPE);
+            headers.insert(
+                http::header::CONTENT_RANGE,
+                HeaderValue::from_str(format!("bytes */{}", body.len()).as_str()).unwrap(),
+            );
+            (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response()
         }
-        Ok(_) => {
-            tracing_subscriber::fmt::init();
-            tracing::info!("honeycomb key file is empty");
+        [range] => {
+            headers.insert(
+                http::header::CONTENT_RANGE,
+                HeaderValue::from_str(content_range(range).as_str()).unwrap(),
+            );
+            (
+                StatusCode::PARTIAL_CONTENT,
+                headers,
+                body.slice(range.clone()),
+            )
+                .into_response()
         }
-        Err(e) => {
-            tracing_subscriber::fmt::init();
-            tracing::info!("couldn't read honeycomb key file: {}", e.to_string());
+        _ => {
+            let content_type = headers.remove(http::header::CONTENT_TYPE).unwrap();
+            let parts: Vec<&[u8]> = ranges.iter().map(|r| &body[r.clone()]).collect();
+            let boundary = multipart_boundary(boundary_seed, &parts);
+            let mut out = vec![];
+            for (range, part) in ranges.iter().zip(parts) {
+                out.extend_from_slice(format!("\r\n--{}\r\n", boundary).as_bytes());
+                out.extend_from_slice(b"Content-Type: ");
+                out.extend_from_slice(content_type.as_bytes());
+                out.extend_from_slice(
+                    format!("\r\nContent-Range: {}\r\n\r\n", content_range(range)).as_bytes(),
+                );
+                out.extend_from_slice(part);
+            }
+            out.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+            headers.insert(
+                http::header::CONTENT_TYPE,
+                HeaderValue::from_str(
+                    format!("multipart/byteranges; boundary={}", boundary).as_str(),
+                )
+                .unwrap(),
+            );
+            (StatusCode::PARTIAL_CONTENT, headers, out).into_response()
         }
     }
+}
 
-    let addr = SocketAddr::from((
-        args.bind_address.unwrap_or([0, 0, 0, 0, 0, 0, 0, 0].into()),
-        args.bind_port.unwrap_or(8080),
-    ));
-
-    let app = setup_router(args.external_url_prefix.clone().unwrap_or("".to_string()))
-        .into_make_service();
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    tracing::info!(
-        "server is listening on http://{}...",
-        listener.local_addr().unwrap()
+/// Redirect to the canonical form of a url, keeping the query string.
+fn gen_canonical_redirect(
+    state: &SharedState,
+    mut location: String,
+    query: Option<&str>,
+) -> Response {
+    // a location starting with two slashes is protocol relative and would send the client to another host
+    if location.starts_with('/') && !is_same_site_location(&location) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if let Some(query) = query {
+        location.push('?');
+        location.push_str(query);
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        http::header::LOCATION,
+        HeaderValue::from_str(location.as_str()).unwrap(),
     );
-    let svr = axum::serve(listener, app);
+    insert_cache_headers(&mut headers, state.cache_policy(CacheClass::Redirect));
+    (state.canonical_redirect_status, headers).into_response()
+}
 
-    if let Err(err) = svr.await {
-        tracing::error!("server error: {}", err);
+/// Whether a location is a path on this site: it starts with a single slash, rather than two slashes or a backslash
+/// which browsers follow to another host.
+fn is_same_site_location(location: &str) -> bool {
+    location.starts_with('/') && !location.starts_with("//") && !location.starts_with("/\\")
+}
+
+/// Normalise the percent-encoding of a path as described in RFC 3986 sections 6.2.2.1 and 6.2.2.2: percent-encoded
+/// unreserved characters are decoded and the hex digits of the other percent-encodings are uppercased. Repeated
+/// slashes are collapsed since no path has empty segments. Paths are case sensitive so the rest is left alone.
+fn normalize_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'/' && out.last() == Some(&b'/') {
+            i += 1;
+            continue;
+        }
+        let decoded = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(c) if c.is_ascii_alphanumeric() || b"-._~".contains(&c) => {
+                out.push(c);
+                i += 3;
+            }
+            Some(c) => {
+                out.extend_from_slice(format!("%{:02X}", c).as_bytes());
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(out).unwrap_or(path.to_string())
+}
+
+/// Whether a request path, including the base path, is served from the item tree or is the search page. Paths that
+/// are still percent-encoded are conservatively reported as missing.
+fn path_exists(state: &SharedState, path: &str) -> bool {
+    match path.strip_prefix(state.base_path.as_str()) {
+        Some("/search") => true,
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            find_item(&state.root, rest).is_some()
+        }
+        _ => false,
+    }
+}
+
+/// Redirect requests to the canonical form of their url before they are routed: to the host of the external url
+/// prefix when enabled, and to the normalised path.
+async fn canonicalize_request(
+    state: State<Arc<SharedState>>,
+    req: Request<axum::body::Body>,
+    next: axum::middleware::Next,
+) -> Response {
+    let path = req.uri().path();
+    // health checks and metrics scrapes come from load balancers and orchestrators that address the server directly
+    if path == "/livez" || path == "/readyz" || path == "/metricz" {
+        return next.run(req).await;
+    }
+    let normalized = normalize_path(path);
+    // paths are case sensitive, so a request is only sent to the lowercase path when it would otherwise miss
+    let lowercase = match normalized.strip_prefix(state.base_path.as_str()) {
+        Some(rest) => format!("{}{}", state.base_path, rest.to_ascii_lowercase()),
+        None => normalized.to_ascii_lowercase(),
+    };
+    let normalized = match lowercase != normalized
+        && !path_exists(&state, &normalized)
+        && path_exists(&state, &lowercase)
+    {
+        true => lowercase,
+        false => normalized,
+    };
+    // the index of a site mounted under a base path is at the base path with a trailing slash, like any other page
+    let normalized = match !state.base_path.is_empty() && normalized == state.base_path {
+        true => format!("{}/", normalized),
+        false => normalized,
+    };
+    if let Some(prefix) = state.canonical_prefix.as_ref() {
+        let host = req
+            .headers()
+            .get(http::header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .or(req.uri().host());
+        let canonical_host = prefix
+            .split_once("://")
+            .map(|(_, rest)| rest)
+            .unwrap_or(prefix)
+            .split('/')
+            .next()
+            .unwrap_or_default();
+        if host
+            .map(|h| !h.eq_ignore_ascii_case(canonical_host))
+            .unwrap_or_default()
+        {
+            // the normalised path already starts with the base path, so only the origin of the prefix is needed
+            let origin = prefix.strip_suffix(&state.base_path).unwrap_or(prefix);
+            return gen_canonical_redirect(
+                &state,
+                format!("{}{}", origin, normalized),
+                req.uri().query(),
+            );
+        }
+    }
+    if normalized != path {
+        return gen_canonical_redirect(&state, normalized, req.uri().query());
+    }
+    next.run(req).await
+}
+
+/// The bytes of content held by an item and everything below it.
+fn item_bytes(item: &Item) -> usize {
+    item.content.len()
+        + item.encoded.iter().map(|(_, e)| e.len()).sum::<usize>()
+        + item.image_variants.iter().map(item_bytes).sum::<usize>()
+        + item
+            .children
+            .values()
+            // fingerprinted assets share the bytes of the asset under its plain name
+            .filter(|c| c.cache_class != CacheClass::Immutable)
+            .map(|c| item_bytes(c))
+            .sum::<usize>()
+}
+
+/// Group the paths that were not found so that the metrics show what is being looked for without a label per path:
+/// scanners probing for other software, pages, or files.
+fn not_found_class(path: &str) -> &'static str {
+    let lower = path.to_ascii_lowercase();
+    let last = lower.rsplit('/').next().unwrap_or_default();
+    if lower.contains("/.")
+        || lower.contains("wp-")
+        || [".php", ".asp", ".aspx", ".cgi", ".env", ".git"]
+            .iter()
+            .any(|e| lower.contains(e))
+    {
+        "probe"
+    } else if !last.contains('.') {
+        "page"
+    } else {
+        "file"
+    }
+}
+
+/// Count each request by route and status, time it, and tally the bytes sent, conditional hits and misses.
+async fn record_metrics(
+    state: State<Arc<SharedState>>,
+    req: Request<axum::body::Body>,
+    next: axum::middleware::Next,
+) -> Response {
+    let start = std::time::Instant::now();
+    let route = req
+        .extensions()
+        .get::<axum::extract::MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or("fallback".to_string());
+    let conditional = req.headers().contains_key(http::header::IF_NONE_MATCH)
+        || req.headers().contains_key(http::header::IF_MODIFIED_SINCE);
+    let path = req.uri().path().to_string();
+    let head = req.method() == http::Method::HEAD;
+
+    let resp = next.run(req).await;
+
+    let status = resp.status();
+    let encoding = match resp.headers().get(http::header::CONTENT_ENCODING) {
+        Some(e) => ContentEncoding::PREFERENCE
+            .into_iter()
+            .find(|c| c.as_str().as_bytes() == e.as_bytes())
+            .unwrap_or(ContentEncoding::Identity),
+        None => ContentEncoding::Identity,
+    };
+    // every body is built in full, so its size is known even where no Content-Length has been set yet, and the body of
+    // a HEAD response is dropped by the server before it is sent
+    let bytes = match head {
+        true => 0,
+        false => axum::body::HttpBody::size_hint(resp.body())
+            .exact()
+            .unwrap_or_default(),
+    };
+    let mut metrics = state.metrics.lock().unwrap();
+    *metrics
+        .requests
+        .entry((route.clone(), status.as_u16()))
+        .or_default() += 1;
+    metrics
+        .durations
+        .entry(route)
+        .or_default()
+        .observe(start.elapsed().as_secs_f64());
+    *metrics.bytes.entry(encoding.as_str()).or_default() += bytes;
+    if conditional {
+        metrics.conditional_requests += 1;
+        if status == StatusCode::NOT_MODIFIED {
+            metrics.not_modified += 1;
+        }
+    }
+    if status == StatusCode::NOT_FOUND {
+        *metrics.not_found.entry(not_found_class(&path)).or_default() += 1;
+    }
+    drop(metrics);
+    resp
+}
+
+/// Escape a Prometheus label value.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render the request, state and process metrics in the Prometheus text exposition format.
+fn render_metrics(state: &SharedState) -> String {
+    use std::fmt::Write;
+    let mut out = String::new();
+    let metrics = state.metrics.lock().unwrap();
+
+    out.push_str("# HELP http_requests_total Requests by route and status.\n");
+    out.push_str("# TYPE http_requests_total counter\n");
+    for ((route, status), count) in metrics.requests.iter() {
+        let _ = writeln!(
+            out,
+            "http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+            escape_label(route),
+            status,
+            count
+        );
+    }
+
+    out.push_str("# HELP http_request_duration_seconds Request latency by route.\n");
+    out.push_str("# TYPE http_request_duration_seconds histogram\n");
+    for (route, histogram) in metrics.durations.iter() {
+        let route = escape_label(route);
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                route, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}",
+            route, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_sum{{route=\"{}\"}} {}",
+            route, histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_count{{route=\"{}\"}} {}",
+            route, histogram.count
+        );
+    }
+
+    out.push_str("# HELP http_response_bytes_total Body bytes sent by content encoding.\n");
+    out.push_str("# TYPE http_response_bytes_total counter\n");
+    for (encoding, bytes) in metrics.bytes.iter() {
+        let _ = writeln!(
+            out,
+            "http_response_bytes_total{{encoding=\"{}\"}} {}",
+            encoding, bytes
+        );
+    }
+
+    out.push_str("# HELP http_conditional_requests_total Requests with If-None-Match or If-Modified-Since.\n");
+    out.push_str("# TYPE http_conditional_requests_total counter\n");
+    let _ = writeln!(
+        out,
+        "http_conditional_requests_total {}",
+        metrics.conditional_requests
+    );
+    out.push_str(
+        "# HELP http_not_modified_total Conditional requests answered with 304 Not Modified.\n",
+    );
+    out.push_str("# TYPE http_not_modified_total counter\n");
+    let _ = writeln!(out, "http_not_modified_total {}", metrics.not_modified);
+    out.push_str("# HELP http_not_modified_ratio The share of conditional requests answered with 304 Not Modified.\n");
+    out.push_str("# TYPE http_not_modified_ratio gauge\n");
+    let ratio = match metrics.conditional_requests {
+        0 => 0.0,
+        n => metrics.not_modified as f64 / n as f64,
+    };
+    let _ = writeln!(out, "http_not_modified_ratio {}", ratio);
+
+    out.push_str(
+        "# HELP http_not_found_total Not found responses by the class of path requested.\n",
+    );
+    out.push_str("# TYPE http_not_found_total counter\n");
+    for (class, count) in metrics.not_found.iter() {
+        let _ = writeln!(out, "http_not_found_total{{class=\"{}\"}} {}", class, count);
+    }
+    drop(metrics);
+
+    out.push_str("# HELP blog_state_bytes Bytes of content held by the shared state across every encoding.\n");
+    out.push_str("# TYPE blog_state_bytes gauge\n");
+    let _ = writeln!(out, "blog_state_bytes {}", state.state_bytes);
+    out.push_str("# HELP blog_posts The number of posts loaded.\n");
+    out.push_str("# TYPE blog_posts gauge\n");
+    let _ = writeln!(out, "blog_posts {}", state.post_count);
+
+    render_process_metrics(&mut out);
+    out
+}
+
+/// The user and system cpu time of every thread of the process from the contents of /proc/self/stat, which counts
+/// them in clock ticks.
+fn process_cpu_seconds(stat: &str) -> Option<f64> {
+    // the command name is in parentheses and may contain spaces, so count the fields from the end of it: utime and
+    // stime are the 14th and 15th fields, where the state following the name is the 3rd
+    let mut fields = stat
+        .get(stat.rfind(')')? + 1..)?
+        .split_whitespace()
+        .skip(11);
+    let utime = fields.next()?.parse::<u64>().ok()?;
+    let stime = fields.next()?.parse::<u64>().ok()?;
+    // SAFETY: sysconf only reads a configuration value
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_second <= 0 {
+        return None;
+    }
+    Some((utime + stime) as f64 / ticks_per_second as f64)
+}
+
+/// Render the standard process metrics from procfs, leaving out any that can't be read on this platform.
+fn render_process_metrics(out: &mut String) {
+    use std::fmt::Write;
+    let mut gauge = |name: &str, kind: &str, help: &str, value: f64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value);
+    };
+    if let Some(cpu_seconds) = std::fs::read_to_string("/proc/self/stat")
+        .ok()
+        .and_then(|stat| process_cpu_seconds(&stat))
+    {
+        gauge(
+            "process_cpu_seconds_total",
+            "counter",
+            "Total user and system CPU time spent in seconds.",
+            cpu_seconds,
+        );
+    }
+    if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+        let kilobytes = |key: &str| {
+            status
+                .lines()
+                .find_map(|l| l.strip_prefix(key))
+                .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        };
+        if let Some(rss) = kilobytes("VmRSS:") {
+            gauge(
+                "process_resident_memory_bytes",
+                "gauge",
+                "Resident memory size in bytes.",
+                (rss * 1024) as f64,
+            );
+        }
+        if let Some(vsz) = kilobytes("VmSize:") {
+            gauge(
+                "process_virtual_memory_bytes",
+                "gauge",
+                "Virtual memory size in bytes.",
+                (vsz * 1024) as f64,
+            );
+        }
+    }
+    if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+        gauge(
+            "process_open_fds",
+            "gauge",
+            "Number of open file descriptors.",
+            fds.count() as f64,
+        );
+    }
+    if let Ok(now) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        gauge(
+            "process_start_time_seconds",
+            "gauge",
+            "Start time of the process since unix epoch in seconds.",
+            now.saturating_sub(START_TIME.elapsed())
+                .as_secs_f64()
+                .floor(),
+        );
+    }
+}
+
+async fn metrics(state: State<Arc<SharedState>>) -> Response {
+    (
+        [
+            (http::header::CONTENT_TYPE, METRICS_CONTENT_TYPE),
+            (http::header::CACHE_CONTROL, "no-store"),
+        ],
+        render_metrics(&state),
+    )
+        .into_response()
+}
+
+/// Add the Strict-Transport-Security header to every response when the server terminates TLS.
+async fn insert_strict_transport_security(
+    state: State<Arc<SharedState>>,
+    req: Request<axum::body::Body>,
+    next: axum::middleware::Next,
+) -> Response {
+    let mut resp = next.run(req).await;
+    if let Some(value) = state.strict_transport_security.as_ref() {
+        resp.headers_mut()
+            .insert(http::header::STRICT_TRANSPORT_SECURITY, value.clone());
+    }
+    resp
+}
+
+#[derive(Clone)]
+struct HttpsRedirectState {
+    https_port: u16,
+    /// The state of the site, so that the health checks of both listeners agree.
+    site: Arc<SharedState>,
+}
+
+impl axum::extract::FromRef<HttpsRedirectState> for Arc<SharedState> {
+    fn from_ref(state: &HttpsRedirectState) -> Self {
+        state.site.clone()
+    }
+}
+
+/// Redirect a plaintext request to the same host and path over https, on the given port.
+async fn redirect_to_https(
+    state: State<HttpsRedirectState>,
+    req: Request<axum::body::Body>,
+) -> Response {
+    let host = req
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or(req.uri().host())
+        .unwrap_or_default();
+    // drop any port from the host, taking care not to split an ipv6 address
+    let host = match host.rsplit_once(':') {
+        Some((h, port)) if !port.contains(']') => h,
+        _ => host,
+    };
+    if host.is_empty() {
+        return (StatusCode::BAD_REQUEST).into_response();
+    }
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let location = match state.https_port {
+        443 => format!("https://{}{}", host, path_and_query),
+        port => format!("https://{}:{}{}", host, port, path_and_query),
+    };
+    match HeaderValue::from_str(&location) {
+        Ok(location) => (
+            StatusCode::PERMANENT_REDIRECT,
+            [(http::header::LOCATION, location)],
+        )
+            .into_response(),
+        Err(_) => (StatusCode::BAD_REQUEST).into_response(),
+    }
+}
+
+/// The router of the plaintext listener that sits next to the TLS one: health checks are answered directly so that
+/// they don't depend on the certificate, and everything else is redirected to https.
+fn setup_https_redirect_router(https_port: u16, site: Arc<SharedState>) -> Router {
+    Router::new()
+        .route("/livez", get(liveness))
+        .route("/readyz", get(readiness))
+        .fallback(redirect_to_https)
+        .with_state(HttpsRedirectState { https_port, site })
+}
+
+/// Reload the TLS certificate and key whenever either file changes, checking their modification times on an interval.
+/// A failed reload keeps the previous certificate so that a half-written file doesn't take the site down.
+async fn reload_tls_on_change(
+    config: axum_server::tls_rustls::RustlsConfig,
+    cert: PathBuf,
+    key: PathBuf,
+    interval: std::time::Duration,
+) {
+    let modified = || async {
+        let cert = tokio::fs::metadata(&cert)
+            .await
+            .and_then(|m| m.modified())
+            .ok();
+        let key = tokio::fs::metadata(&key)
+            .await
+            .and_then(|m| m.modified())
+            .ok();
+        (cert, key)
+    };
+    let mut last = modified().await;
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let current = modified().await;
+        if current == last {
+            continue;
+        }
+        match config.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => {
+                tracing::info!("reloaded tls certificate from {}", cert.display());
+                last = current;
+            }
+            Err(e) => tracing::error!("failed to reload tls certificate: {}", e),
+        }
+    }
+}
+
+/// Alive unless the watchdog has stopped checking in. With ?verbose the result of each check is returned as json.
+async fn liveness(
+    state: State<Arc<SharedState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let checks = [(
+        "watchdog",
+        !state.watchdog.is_stalled(WATCHDOG_STALL_THRESHOLD),
+    )];
+    health_response(&state, &checks, params.contains_key("verbose"))
+}
+
+/// Ready until a shutdown starts. The listeners only bind once the shared state is built, so there is no loading state
+/// to report. With ?verbose the result of each check is returned as json.
+async fn readiness(
+    state: State<Arc<SharedState>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let checks = [("draining", !state.draining.load(Ordering::Relaxed))];
+    health_response(&state, &checks, params.contains_key("verbose"))
+}
+
+/// Respond 204 when every check passes and 503 otherwise, or with the detail of the checks when verbose.
+fn health_response(state: &SharedState, checks: &[(&str, bool)], verbose: bool) -> Response {
+    let healthy = checks.iter().all(|(_, pass)| *pass);
+    if !verbose {
+        return match healthy {
+            true => StatusCode::NO_CONTENT,
+            false => StatusCode::SERVICE_UNAVAILABLE,
+        }
+        .into_response();
+    }
+    let body = serde_json::json!({
+        "status": if healthy { "pass" } else { "fail" },
+        "checks": checks
+            .iter()
+            .map(|(name, pass)| (name.to_string(), serde_json::json!(if *pass { "pass" } else { "fail" })))
+            .collect::<serde_json::Map<String, serde_json::Value>>(),
+        "posts": state.post_count,
+        "uptime_seconds": START_TIME.elapsed().as_secs(),
+        "version": CRATE_VERSION,
+        "pid": std::process::id(),
+        "start_time": (OffsetDateTime::now_utc() - START_TIME.elapsed())
+            .format(&RFC3339_DATE_FORMAT)
+            .unwrap(),
+    });
+    let status = match healthy {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (
+        status,
+        [
+            (http::header::CONTENT_TYPE, "application/json"),
+            (http::header::CACHE_CONTROL, "no-store"),
+        ],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+/// Wait for SIGTERM or SIGINT, or just SIGINT when the SIGTERM handler can't be installed.
+async fn shutdown_signal() -> &'static str {
+    let sigterm = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                tracing::error!("failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = sigterm => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+/// Once a shutdown signal arrives, fail the readiness check and keep serving for the drain period, then tell the
+/// listeners to stop accepting connections. A second signal during the drain period exits straight away.
+async fn drain_on_shutdown_signal(
+    draining: Arc<AtomicBool>,
+    drain_period: Duration,
+    shutdown: tokio::sync::watch::Sender<bool>,
+) {
+    let signal = shutdown_signal().await;
+    tracing::info!(
+        "received {}, draining for {}s before closing the listeners",
+        signal,
+        drain_period.as_secs()
+    );
+    draining.store(true, Ordering::Relaxed);
+    tokio::select! {
+        _ = tokio::time::sleep(drain_period) => {},
+        signal = shutdown_signal() => {
+            tracing::warn!("received {} while draining, exiting now", signal);
+            std::process::exit(1);
+        }
+    }
+    tracing::info!("closing the listeners and waiting for in-flight requests");
+    shutdown.send_replace(true);
+}
+
+/// Resolve once the drain period is over and the listeners should close.
+async fn listeners_closed(mut shutdown: tokio::sync::watch::Receiver<bool>) {
+    // the sender is only dropped without closing the listeners if the drain task panicked, and then there will be no
+    // drain period to wait for, so keep serving rather than shutting down without one
+    if shutdown.wait_for(|closed| *closed).await.is_err() {
+        tracing::error!("shutdown signal handling stopped, serving until killed");
+        std::future::pending::<()>().await;
+    }
+}
+
+#[derive(Default, Clone)]
+struct HttpTraceLayerHooks;
+
+impl<B> MakeSpan<B> for HttpTraceLayerHooks {
+    fn make_span(&mut self, req: &Request<B>) -> Span {
+        // see https://github.com/open-telemetry/semantic-conventions/blob/main/docs/http/http-spans.md
+        let span = tracing::info_span!(
+            // span name here will be ignored by open telemetry and replaced with otel.name
+            "request",
+            // the span name
+            otel.name = tracing::field::Empty,
+            otel.kind = "server",
+            otel.status_code = tracing::field::Empty,
+            // common attributes
+            http.response.status_code = tracing::field::Empty,
+            http.request.body.size = tracing::field::Empty,
+            http.response.body.size = tracing::field::Empty,
+            http.request.method = req.method().as_str(),
+            network.protocol.name = "http",
+            network.protocol.version = format!("{:?}", req.version()).strip_prefix("HTTP/"),
+            user_agent.original = tracing::field::Empty,
+            // http request and response headers
+            http.request.header.x_forwarded_for = tracing::field::Empty,
+            http.request.header.cf_ipcountry = tracing::field::Empty,
+            http.request.header.referer = tracing::field::Empty,
+            // http server
+            http.route = tracing::field::Empty,
+            server.address = tracing::field::Empty,
+            server.port = tracing::field::Empty,
+            url.path = req.uri().path(),
+            url.query = tracing::field::Empty,
+            url.scheme = tracing::field::Empty,
+            // set on the failure hook
+            "error.type" = tracing::field::Empty,
+            error = tracing::field::Empty,
+        );
+
+        req.uri().query().map(|v| span.record("url.query", v));
+        req.uri()
+            .scheme()
+            .map(|v| span.record("url.scheme", v.as_str()));
+        req.uri().host().map(|v| span.record("server.address", v));
+        req.uri().port_u16().map(|v| span.record("server.port", v));
+
+        req.headers()
+            .get(http::header::CONTENT_LENGTH)
+            .map(|v| v.to_str().map(|v| span.record("http.request.body.size", v)));
+        req.headers()
+            .get(http::header::USER_AGENT)
+            .map(|v| v.to_str().map(|v| span.record("user_agent.original", v)));
+        req.headers().get("X-Forwarded-For").map(|v| {
+            v.to_str()
+                .map(|v| span.record("http.request.header.x_forwarded_for", v))
+        });
+        req.headers().get("CF-IPCountry").map(|v| {
+            v.to_str()
+                .map(|v| span.record("http.request.header.cf_ipcountry", v))
+        });
+        req.headers().get("Referer").map(|v| {
+            v.to_str()
+                .map(|v| span.record("http.request.header.referer", v))
+        });
+
+        if let Some(path) = req.extensions().get::<axum::extract::MatchedPath>() {
+            span.record("otel.name", format!("{} {}", req.method(), path.as_str()));
+            span.record("http.route", path.as_str());
+        } else {
+            span.record("otel.name", format!("{} -", req.method()));
+        };
+
+        span
+    }
+}
+
+impl<B> tower_http::trace::OnRequest<B> for HttpTraceLayerHooks {
+    fn on_request(&mut self, _: &Request<B>, _: &Span) {
+        tracing::event!(tracing::Level::DEBUG, "start processing request");
+    }
+}
+
+impl<B> tower_http::trace::OnResponse<B> for HttpTraceLayerHooks {
+    fn on_response(self, response: &Response<B>, _: std::time::Duration, span: &Span) {
+        if let Some(size) = response.headers().get(http::header::CONTENT_LENGTH) {
+            span.record("http.response.body.size", size.to_str().unwrap());
+        }
+        span.record("http.response.status_code", response.status().as_u16());
+
+        // Server errors are handled by the OnFailure hook
+        if !response.status().is_server_error() {
+            tracing::event!(tracing::Level::INFO, "finished processing request");
+        }
+    }
+}
+
+impl<FailureClass> tower_http::trace::OnFailure<FailureClass> for HttpTraceLayerHooks
+where
+    FailureClass: std::fmt::Display,
+{
+    fn on_failure(&mut self, error: FailureClass, _: std::time::Duration, _: &Span) {
+        tracing::event!(
+            tracing::Level::ERROR,
+            error = error.to_string(),
+            "error.type" = "_OTHER",
+            "response failed"
+        );
+    }
+}
+
+fn setup_router(state: Arc<SharedState>) -> Router {
+    let trace_layer = TraceLayer::new_for_http()
+        .make_span_with(HttpTraceLayerHooks)
+        .on_request(HttpTraceLayerHooks)
+        .on_response(HttpTraceLayerHooks)
+        .on_failure(HttpTraceLayerHooks);
+    let site = Router::new()
+        .route("/", get(view_root_item))
+        .route("/search", get(search))
+        .route("/:a", get(view_item))
+        .route("/:a/", get(view_item))
+        .route("/:a/:b", get(view_nested_item))
+        .route("/:a/:b/", get(view_nested_item))
+        .route("/:a/:b/:c", get(view_deep_item))
+        .route("/:a/:b/:c/", get(view_deep_item))
+        .fallback(not_found);
+    // the health checks stay at the root so that they don't depend on where the site is mounted
+    let router = match state.base_path.as_str() {
+        "" => site,
+        // a nested root route only matches the base path without the trailing slash, which is redirected to the
+        // base path with one before it gets here. Crawlers only look for robots.txt at the root of the host, and its
+        // rules are written for there.
+        base_path => Router::new()
+            .route(&format!("{}/", base_path), get(view_root_item))
+            .route(
+                "/robots.txt",
+                get(
+                    |state: State<Arc<SharedState>>,
+                     req_headers: HeaderMap,
+                     req: Request<axum::body::Body>| async move {
+                        view_item_at_path(&["robots.txt".to_string()], state, req_headers, req)
+                    },
+                ),
+            )
+            .nest(base_path, site)
+            .fallback(
+                |state: State<Arc<SharedState>>, req_headers: HeaderMap| async move {
+                    gen_not_found(state, req_headers)
+                },
+            ),
+    };
+    router
+        .route("/livez", get(liveness))
+        .route("/readyz", get(readiness))
+        .route("/metricz", get(metrics))
+        .with_state(state.clone())
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            canonicalize_request,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            insert_strict_transport_security,
+        ))
+        .layer(axum::middleware::from_fn_with_state(state, record_metrics))
+        .layer(trace_layer)
+}
+
+#[derive(Debug, Clone)]
+struct CustomSampler {}
+
+impl ShouldSample for CustomSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        _trace_id: TraceId,
+        _name: &str,
+        _span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        _links: &[Link],
+    ) -> SamplingResult {
+        let mut decision = SamplingDecision::RecordAndSample;
+        for a in attributes {
+            if a.key.as_str().eq("http.route")
+                && (a.value.as_str().eq("/livez")
+                    || a.value.as_str().eq("/readyz")
+                    || a.value.as_str().eq("/metricz"))
+            {
+                decision = SamplingDecision::Drop;
+                break;
+            }
+        }
+        SamplingResult {
+            decision,
+            // No extra attributes ever set by the SDK samplers.
+            attributes: Vec::new(),
+            // all sampler in SDK will not modify trace state.
+            trace_state: match parent_context {
+                Some(ctx) => ctx.span().span_context().trace_state().clone(),
+                None => TraceState::default(),
+            },
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    lazy_static::initialize(&START_TIME);
+    let mut args = Cli::parse();
+    if args.external_url_prefix.is_none() {
+        args.external_url_prefix = std::env::var("EXTERNAL_URL_PREFIX").ok()
+    }
+    if let Some(base_path) = args.base_path.as_ref() {
+        let base_path = format!("/{}", base_path.trim_matches('/'));
+        let prefix = args.external_url_prefix.get_or_insert_with(String::new);
+        if base_path != "/" && url_base_path(prefix) != base_path {
+            prefix.truncate(prefix.trim_end_matches('/').len());
+            prefix.push_str(&base_path);
+        }
+    }
+    let honeycomb_key_path =
+        std::env::var("HONEYCOMB_KEY_PATH").unwrap_or_else(|_| "honeycomb.key".to_string());
+    match std::fs::read_to_string(honeycomb_key_path) {
+        Ok(api_key) if !api_key.trim().is_empty() => {
+            let otlp_endpoint = "https://api.honeycomb.io";
+            let otlp_headers =
+                HashMap::from([("x-honeycomb-team".into(), api_key.trim().to_string())]);
+            let otlp_service_name = args
+                .external_url_prefix
+                .clone()
+                .unwrap_or("unknown".to_string())
+                .replace("://", "-")
+                .replace(['.', '/'], "-");
+
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .http()
+                        .with_endpoint(otlp_endpoint)
+                        .with_http_client(reqwest::Client::default())
+                        .with_headers(otlp_headers)
+                        .with_timeout(std::time::Duration::from_secs(5)),
+                ) // Replace with runtime::Tokio if using async main
+                .with_trace_config(
+                    opentelemetry_sdk::trace::config()
+                        .with_sampler(CustomSampler {})
+                        .with_resource(opentelemetry_sdk::Resource::new(vec![
+                            opentelemetry::KeyValue::new("service.name", otlp_service_name),
+                        ])),
+                )
+                .install_batch(opentelemetry_sdk::runtime::Tokio)
+                .unwrap();
+
+            let subscriber = tracing_subscriber::registry::Registry::default()
+                .with(tracing_subscriber::filter::LevelFilter::INFO) // filter out low-level debug tracing (eg tokio executor)
+                .with(tracing_subscriber::fmt::Layer::default()) // log to stdout
+                .with(tracing_opentelemetry::layer().with_tracer(tracer)); // traces can go to open telemetry
+            tracing::subscriber::set_global_default(subscriber)
+                .expect("failed to set tracing subscriber");
+            tracing::info!("set up honeycomb tracing");
+        }
+        Ok(_) => {
+            tracing_subscriber::fmt::init();
+            tracing::info!("honeycomb key file is empty");
+        }
+        Err(e) => {
+            tracing_subscriber::fmt::init();
+            tracing::info!("couldn't read honeycomb key file: {}", e.to_string());
+        }
+    }
+
+    let addr = SocketAddr::from((
+        args.bind_address.unwrap_or([0, 0, 0, 0, 0, 0, 0, 0].into()),
+        args.bind_port.unwrap_or(8080),
+    ));
+
+    let mut options = SiteOptions {
+        index_page_size: args.index_page_size,
+        index_summaries: args.index_summaries,
+        ..SiteOptions::default()
+    };
+    options.canonical_redirect_status =
+        StatusCode::from_u16(args.canonical_redirect_status).unwrap();
+    options.canonical_host_redirect = args.canonical_host_redirect;
+    if let Some(path) = args.redirects.as_ref() {
+        let source = std::fs::read_to_string(path).expect("failed to read redirects file");
+        options.redirects = parse_redirects(&source)
+            .unwrap_or_else(|e| panic!("invalid redirects file {}: {}", path.display(), e));
+    }
+    for (class, directives) in args.cache_policy.iter() {
+        options.cache_policies.get_mut(class).unwrap().cache_control = directives.clone();
+    }
+    for (class, directives) in args.cdn_cache_policy.iter() {
+        options
+            .cache_policies
+            .get_mut(class)
+            .unwrap()
+            .cdn_cache_control = Some(directives.clone());
+    }
+    for (class, directives) in args.cloudflare_cache_policy.iter() {
+        options
+            .cache_policies
+            .get_mut(class)
+            .unwrap()
+            .cloudflare_cdn_cache_control = Some(directives.clone());
+    }
+    if args.tls_cert.is_some() && args.hsts_max_age > 0 {
+        options.strict_transport_security = Some(format!("max-age={}", args.hsts_max_age));
+    }
+    let external_url_prefix = args.external_url_prefix.clone().unwrap_or("".to_string());
+    let state = Arc::new(build_shared_state(
+        collect_posts(&external_url_prefix),
+        &external_url_prefix,
+        &options,
+    ));
+    let draining = state.draining.clone();
+    tokio::spawn(run_watchdog(state.clone()));
+    let app = setup_router(state.clone()).into_make_service();
+    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    tokio::spawn(drain_on_shutdown_signal(
+        draining.clone(),
+        Duration::from_secs(args.drain_period),
+        shutdown_tx,
+    ));
+    let shutdown_deadline = Duration::from_secs(args.shutdown_deadline);
+
+    match (args.tls_cert.clone(), args.tls_key.clone()) {
+        (Some(cert), Some(key)) => {
+            let tls = TlsOptions {
+                cert,
+                key,
+                reload_interval: Duration::from_secs(args.tls_reload_interval.max(1)),
+                http_redirect_port: args.http_redirect_port,
+            };
+            serve_tls(listener, app, tls, state, shutdown_rx, shutdown_deadline).await
+        }
+        _ => {
+            tracing::info!(
+                "server is listening on http://{}...",
+                listener.local_addr().unwrap()
+            );
+            let svr = axum::serve(listener, app)
+                .with_graceful_shutdown(listeners_closed(shutdown_rx.clone()))
+                .into_future();
+            let deadline = async {
+                listeners_closed(shutdown_rx).await;
+                tokio::time::sleep(shutdown_deadline).await;
+            };
+
+            tokio::select! {
+                result = svr => if let Err(err) = result {
+                    tracing::error!("server error: {}", err);
+                },
+                _ = deadline => tracing::warn!("shutdown deadline passed with requests still in flight"),
+            }
+        }
+    }
+
+    // the batch exporter holds spans that haven't been sent yet, and flushing it blocks
+    tracing::info!("flushing traces before exiting");
+    let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+}
+
+struct TlsOptions {
+    cert: PathBuf,
+    key: PathBuf,
+    reload_interval: Duration,
+    http_redirect_port: Option<u16>,
+}
+
+/// Serve the site over TLS until the listeners close, then give in-flight requests until the deadline to finish.
+async fn serve_tls(
+    listener: tokio::net::TcpListener,
+    app: axum::routing::IntoMakeService<Router>,
+    tls: TlsOptions,
+    state: Arc<SharedState>,
+    shutdown_rx: tokio::sync::watch::Receiver<bool>,
+    shutdown_deadline: Duration,
+) {
+    let addr = listener.local_addr().unwrap();
+
+    // the pem config advertises h2 and http/1.1 over alpn
+    let tls_config = axum_server::tls_rustls::RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+        .await
+        .expect("failed to load tls certificate and key");
+    tokio::spawn(reload_tls_on_change(
+        tls_config.clone(),
+        tls.cert,
+        tls.key,
+        tls.reload_interval,
+    ));
+    if let Some(port) = tls.http_redirect_port {
+        let redirect_addr = SocketAddr::new(addr.ip(), port);
+        let redirect_listener = tokio::net::TcpListener::bind(&redirect_addr).await.unwrap();
+        tracing::info!(
+            "redirecting http://{} to https...",
+            redirect_listener.local_addr().unwrap()
+        );
+        let redirect_app = setup_https_redirect_router(addr.port(), state).into_make_service();
+        let redirect_shutdown = listeners_closed(shutdown_rx.clone());
+        tokio::spawn(async move {
+            let svr = axum::serve(redirect_listener, redirect_app)
+                .with_graceful_shutdown(redirect_shutdown);
+            if let Err(err) = svr.await {
+                tracing::error!("redirect server error: {}", err);
+            }
+        });
+    }
+    tracing::info!(
+        "server is listening on https://{}...",
+        listener.local_addr().unwrap()
+    );
+    let handle = axum_server::Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        listeners_closed(shutdown_rx).await;
+        shutdown_handle.graceful_shutdown(Some(shutdown_deadline));
+    });
+    let svr = axum_server::from_tcp_rustls(listener.into_std().unwrap(), tls_config)
+        .handle(handle)
+        .serve(app);
+
+    if let Err(err) = svr.await {
+        tracing::error!("server error: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::header::{
+        ACCEPT, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_SECURITY_POLICY, CONTENT_TYPE, ETAG,
+    };
+    use axum::http::HeaderName;
+    use axum::http::{HeaderValue, Method, Request, StatusCode};
+    use axum::response::Response;
+    use axum::Router;
+    use http_body_util::BodyExt;
+    use hyper::header::{
+        ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_RANGE, HOST, IF_MATCH,
+        IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, IF_UNMODIFIED_SINCE, LAST_MODIFIED, LOCATION,
+        RANGE, STRICT_TRANSPORT_SECURITY, VARY,
+    };
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::time::Duration;
+    use time::macros::datetime;
+    use time::PrimitiveDateTime;
+    // for `oneshot` and `ready`
+    use test_case::test_case;
+    use tower::ServiceExt;
+
+    use crate::{
+        absolutize_url, asset_fingerprints, build_shared_state, collect_posts,
+        compute_related_posts, encode_variants, entity_tag_list_matches, find_item,
+        fingerprint_asset_name, gen_canonical_redirect, is_compressible, is_same_site_location,
+        listeners_closed, make_csp_hash, make_etag, match_redirect, multipart_boundary,
+        negotiate_encoding, normalize_path, not_found_class, parse_cache_policy_arg,
+        parse_http_date, parse_post, parse_range, parse_redirect_status, parse_redirects,
+        pre_render_footer, pre_render_post, process_cpu_seconds, search_snippet,
+        setup_https_redirect_router, tokenize, truncate_words, url_base_path, validate_paths,
+        validate_redirects, variant_etag, Asset, CacheClass, CachePolicy, ContentEncoding, Item,
+        Redirect, SharedState, SiteOptions, Watchdog, CONTENT_FILE_NAME,
+    };
+
+    /// Building the state compresses every item, so tests share one router per configuration.
+    fn setup_router(external_url_prefix: String, options: SiteOptions) -> Router {
+        static ROUTERS: OnceLock<Mutex<HashMap<String, Router>>> = OnceLock::new();
+        let key = format!("{external_url_prefix} {options:?}");
+        ROUTERS
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| {
+                crate::setup_router(Arc::new(build_shared_state(
+                    collect_posts(&external_url_prefix),
+                    &external_url_prefix,
+                    &options,
+                )))
+            })
+            .clone()
+    }
+
+    /// A state shared by the tests that don't change it.
+    fn test_state() -> Arc<SharedState> {
+        static STATE: OnceLock<Arc<SharedState>> = OnceLock::new();
+        STATE
+            .get_or_init(|| {
+                let external_url_prefix = "http://example".to_string();
+                Arc::new(build_shared_state(
+                    collect_posts(&external_url_prefix),
+                    &external_url_prefix,
+                    &SiteOptions::default(),
+                ))
+            })
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn test_index() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        let length: u32 = resp
+            .headers()
+            .get(CONTENT_LENGTH)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(length > 1);
+        assert!(resp.headers().get(ETAG).is_some());
+        assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+        assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "max-age=300");
+    }
+
+    #[tokio::test]
+    async fn test_redirect_slash() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri("/20230706-binary-blog")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            resp.headers().get(LOCATION).unwrap(),
+            "/20230706-binary-blog/"
+        );
+        let length: u32 = resp
+            .headers()
+            .get(CONTENT_LENGTH)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(length, 0);
+        assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "max-age=300");
+    }
+
+    #[tokio::test]
+    async fn test_index_gzipped() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header(
+                        ACCEPT_ENCODING,
+                        HeaderValue::from_str("deflate,gzip").unwrap(),
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+    }
+
+    #[test_case(None, Some(ContentEncoding::Identity) ; "no header")]
+    #[test_case(Some("gzip, deflate, br, zstd"), Some(ContentEncoding::Brotli) ; "brotli preferred on ties")]
+    #[test_case(Some("br;q=0.5, gzip"), Some(ContentEncoding::Gzip) ; "higher q wins")]
+    #[test_case(Some("x-gzip"), Some(ContentEncoding::Gzip) ; "x-gzip alias")]
+    #[test_case(Some("*"), Some(ContentEncoding::Brotli) ; "wildcard")]
+    #[test_case(Some("br;q=0, *"), Some(ContentEncoding::Zstd) ; "wildcard excluding brotli")]
+    #[test_case(Some("compress"), Some(ContentEncoding::Identity) ; "unknown falls back to identity")]
+    #[test_case(Some("gzip;q=0, identity;q=0"), None ; "nothing acceptable")]
+    #[test_case(Some("*;q=0"), None ; "wildcard excludes identity")]
+    fn test_negotiate_encoding(header: Option<&str>, expected: Option<ContentEncoding>) {
+        let header = header.map(|h| HeaderValue::from_str(h).unwrap());
+        let available = [
+            ContentEncoding::Brotli,
+            ContentEncoding::Zstd,
+            ContentEncoding::Gzip,
+        ];
+        assert_eq!(negotiate_encoding(header.as_ref(), &available), expected);
+    }
+
+    #[test]
+    fn test_negotiate_encoding_only_offers_available() {
+        let header = HeaderValue::from_static("br, gzip;q=0.5");
+        assert_eq!(
+            negotiate_encoding(Some(&header), &[ContentEncoding::Gzip]),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(
+            negotiate_encoding(Some(&header), &[]),
+            Some(ContentEncoding::Identity)
+        );
+    }
+
+    #[test_case("text/html; charset=utf-8", true ; "html")]
+    #[test_case("application/atom+xml", true ; "atom")]
+    #[test_case("application/json", true ; "json")]
+    #[test_case("image/svg+xml", true ; "svg")]
+    #[test_case("image/jpeg", false ; "jpeg")]
+    #[test_case("image/png", false ; "png")]
+    #[test_case("image/webp", false ; "webp")]
+    #[test_case("video/mp4", false ; "video")]
+    #[test_case("font/woff2", false ; "woff2")]
+    #[test_case("application/zip", false ; "zip")]
+    fn test_is_compressible(content_type: &str, expected: bool) {
+        assert_eq!(is_compressible(content_type), expected);
+    }
+
+    #[test]
+    fn test_encode_variants_skips_incompressible() {
+        let text = "hello world ".repeat(100);
+        let encodings: Vec<ContentEncoding> =
+            encode_variants("text", "text/plain", text.as_bytes())
+                .into_iter()
+                .map(|(e, _)| e)
+                .collect();
+        assert_eq!(
+            encodings,
+            vec![
+                ContentEncoding::Brotli,
+                ContentEncoding::Zstd,
+                ContentEncoding::Gzip
+            ]
+        );
+
+        assert!(encode_variants("image", "image/jpeg", text.as_bytes()).is_empty());
+
+        // too short to shrink by a meaningful amount
+        assert!(encode_variants("short", "text/plain", b"hi").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_image_not_compressed() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri("/url-image.jpg")
+                    .header(ACCEPT_ENCODING, "br, zstd, gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), "image/jpeg");
+    }
+
+    #[test]
+    fn test_make_etag() {
+        // the leading half of the well known sha256 of nothing
+        assert_eq!(make_etag(b""), "e3b0c44298fc1c149afbf4c8996fb924");
+        assert_ne!(make_etag(b"one"), make_etag(b"two"));
+        assert_eq!(
+            variant_etag("abc", ContentEncoding::Identity),
+            "\"abc\"".to_string()
+        );
+        assert_eq!(
+            variant_etag("abc", ContentEncoding::Brotli),
+            "\"abc-br\"".to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_etag_per_encoding() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let mut etags = vec![];
+        for encoding in ["identity", "br", "zstd", "gzip"] {
+            let resp = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/")
+                        .header(ACCEPT_ENCODING, encoding)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let etag = resp
+                .headers()
+                .get(ETAG)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned();
+            assert!(etag.starts_with('"') && etag.ends_with('"'));
+            assert!(!etags.contains(&etag));
+            etags.push(etag.clone());
+
+            let resp = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/")
+                        .header(ACCEPT_ENCODING, encoding)
+                        .header(IF_NONE_MATCH, etag.as_str())
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(resp.headers().get(ETAG).unwrap(), etag.as_str());
+        }
+    }
+
+    #[test_case("\"abc\"", false, true ; "exact")]
+    #[test_case("*", false, true ; "any")]
+    #[test_case("\"x\", \"abc\"", false, true ; "list")]
+    #[test_case(" ,\"x\",,\"abc\" ", false, true ; "empty list elements")]
+    #[test_case("\"a,b\", \"abc\"", false, true ; "comma inside tag")]
+    #[test_case("W/\"abc\"", true, true ; "weak comparison")]
+    #[test_case("W/\"abc\"", false, false ; "strong comparison")]
+    #[test_case("\"abd\"", true, false ; "different")]
+    #[test_case("abc", true, false ; "unquoted")]
+    #[test_case("\"abc", true, false ; "unterminated")]
+    fn test_entity_tag_list_matches(header: &str, weak_comparison: bool, expected: bool) {
+        let header = HeaderValue::from_str(header).unwrap();
+        assert_eq!(
+            entity_tag_list_matches(&header, "\"abc\"", weak_comparison),
+            expected
+        );
+    }
+
+    #[test_case("Thu, 06 Jul 2023 00:00:00 GMT", Some(datetime!(2023-07-06 0:00)) ; "imf fixdate")]
+    #[test_case("Thu Jul  6 00:00:00 2023", Some(datetime!(2023-07-06 0:00)) ; "asctime")]
+    #[test_case("Sunday, 06-Nov-94 08:49:37 GMT", Some(datetime!(1994-11-06 8:49:37)) ; "rfc 850")]
+    #[test_case("Thursday, 06-Jul-23 00:00:00 GMT", Some(datetime!(2023-07-06 0:00)) ; "rfc 850 this century")]
+    #[test_case("Sunday, 06-Nov-94 08:49:37 GMT trailing", None ; "rfc 850 trailing")]
+    #[test_case("yesterday", None ; "invalid")]
+    fn test_parse_http_date(header: &str, expected: Option<PrimitiveDateTime>) {
+        assert_eq!(
+            parse_http_date(&HeaderValue::from_str(header).unwrap()),
+            expected
+        );
+    }
+
+    async fn get_with_header(uri: &str, name: HeaderName, value: HeaderValue) -> Response {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        app.oneshot(
+            Request::builder()
+                .uri(uri)
+                .header(name, value)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_conditional_requests() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri("/20230706-binary-blog/")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let etag = resp
+            .headers()
+            .get(ETAG)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        assert_eq!(
+            resp.headers().get(LAST_MODIFIED).unwrap(),
+            "Thu, 06 Jul 2023 00:00:00 GMT"
+        );
+
+        let uri = "/20230706-binary-blog/";
+        let cases = [
+            (
+                IF_NONE_MATCH,
+                format!("\"nope\", W/{}", etag),
+                StatusCode::NOT_MODIFIED,
+            ),
+            (IF_NONE_MATCH, "*".to_string(), StatusCode::NOT_MODIFIED),
+            (IF_NONE_MATCH, "\"nope\"".to_string(), StatusCode::OK),
+            (IF_MATCH, etag.clone(), StatusCode::OK),
+            (
+                IF_MATCH,
+                format!("W/{}", etag),
+                StatusCode::PRECONDITION_FAILED,
+            ),
+            (
+                IF_MATCH,
+                "\"nope\"".to_string(),
+                StatusCode::PRECONDITION_FAILED,
+            ),
+            (
+                IF_MODIFIED_SINCE,
+                "Fri, 07 Jul 2023 00:00:00 GMT".to_string(),
+                StatusCode::NOT_MODIFIED,
+            ),
+            (
+                IF_MODIFIED_SINCE,
+                "Wed, 05 Jul 2023 00:00:00 GMT".to_string(),
+                StatusCode::OK,
+            ),
+            (IF_MODIFIED_SINCE, "garbage".to_string(), StatusCode::OK),
+            (
+                IF_UNMODIFIED_SINCE,
+                "Wed, 05 Jul 2023 00:00:00 GMT".to_string(),
+                StatusCode::PRECONDITION_FAILED,
+            ),
+        ];
+        for (name, value, expected) in cases {
+            let resp =
+                get_with_header(uri, name.clone(), HeaderValue::from_str(&value).unwrap()).await;
+            assert_eq!(resp.status(), expected, "{}: {}", name, value);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_non_ascii_conditional_headers() {
+        let value = HeaderValue::from_bytes(b"\"\xe9t\xe9\"").unwrap();
+        for name in [IF_NONE_MATCH, IF_MATCH, IF_MODIFIED_SINCE, ACCEPT] {
+            let resp = get_with_header("/", name.clone(), value.clone()).await;
+            assert_ne!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        let resp = get_with_header("/missing", ACCEPT, value).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test_case("bytes=0-99", Some(vec![(0, 100)]) ; "first bytes")]
+    #[test_case("bytes=900-", Some(vec![(900, 1000)]) ; "open ended")]
+    #[test_case("bytes=-100", Some(vec![(900, 1000)]) ; "suffix")]
+    #[test_case("bytes=-5000", Some(vec![(0, 1000)]) ; "suffix longer than content")]
+    #[test_case("bytes=990-2000", Some(vec![(990, 1000)]) ; "clamped")]
+    #[test_case("bytes=0-9, 20-29", Some(vec![(0, 10), (20, 30)]) ; "multiple")]
+    #[test_case("bytes=20-29,0-25", Some(vec![(0, 30)]) ; "overlapping coalesced")]
+    #[test_case("bytes=1000-", Some(vec![]) ; "unsatisfiable")]
+    #[test_case("bytes=-0", Some(vec![]) ; "empty suffix")]
+    #[test_case("bytes=9-0", None ; "reversed")]
+    #[test_case("bytes=a-b", None ; "not numbers")]
+    #[test_case("items=0-9", None ; "unknown unit")]
+    #[test_case("bytes=", None ; "no ranges")]
+    #[test_case("bytes=0-0,2-2,4-4,6-6,8-8,10-10,12-12,14-14,16-16,18-18,20-20,22-22,24-24,26-26,28-28,30-30,32-32", None ; "too many ranges")]
+    fn test_parse_range(header: &str, expected: Option<Vec<(usize, usize)>>) {
+        assert_eq!(
+            parse_range(&HeaderValue::from_str(header).unwrap(), 1000),
+            expected.map(|x| x.into_iter().map(|(a, b)| a..b).collect())
+        );
+    }
+
+    async fn get_range(range: &str, if_range: Option<&str>) -> Response {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let mut req = Request::builder()
+            .uri("/url-image.jpg")
+            .header(RANGE, range);
+        if let Some(if_range) = if_range {
+            req = req.header(IF_RANGE, if_range);
+        }
+        app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap()
+    }
+
+    #[test_case(b"no match here", "abc" ; "unused")]
+    #[test_case(b"xxabcxx", "abc-1" ; "in body")]
+    #[test_case(b"abc abc-1 abc-2", "abc-3" ; "suffixed in body")]
+    fn test_multipart_boundary(part: &[u8], expected: &str) {
+        assert_eq!(multipart_boundary("abc", &[b"other", part]), expected);
+    }
+
+    #[tokio::test]
+    async fn test_range_requests() {
+        let whole = Asset::get("url-image.jpg").unwrap().data;
+
+        let resp = get_range("bytes=0-99", None).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers().get(ACCEPT_RANGES).unwrap(), "bytes");
+        assert_eq!(
+            resp.headers().get(CONTENT_RANGE).unwrap().to_str().unwrap(),
+            format!("bytes 0-99/{}", whole.len())
+        );
+        let etag = resp
+            .headers()
+            .get(ETAG)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body.as_ref(), &whole[..100]);
+
+        let resp = get_range("bytes=0-1, 10-11", None).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("multipart/byteranges; boundary="));
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8_lossy(body.as_ref());
+        assert!(body.contains(&format!("Content-Range: bytes 10-11/{}", whole.len())));
+        assert!(body.contains("Content-Type: image/jpeg"));
+
+        let resp = get_range(&format!("bytes={}-", whole.len()), None).await;
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            resp.headers().get(CONTENT_RANGE).unwrap().to_str().unwrap(),
+            format!("bytes */{}", whole.len())
+        );
+
+        let resp = get_range("bytes=0-99", Some(&etag)).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+
+        let resp = get_range("bytes=0-99", Some("\"stale\"")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body.len(), whole.len());
+
+        let resp = get_range("bytes=0-99", Some(&format!("W/{}", etag))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    /// The url that a post links to one of its assets at.
+    fn fingerprinted_url(post: &str, name: &str) -> String {
+        let prefix = format!("posts/{}/", post);
+        let assets: HashMap<String, Cow<'static, [u8]>> = Asset::iter()
+            .filter_map(|a| {
+                let asset_name = a.strip_prefix(prefix.as_str())?.to_string();
+                Some((asset_name, Asset::get(a.as_ref()).unwrap().data))
+            })
+            .collect();
+        format!(
+            "http://example/{}/{}",
+            post,
+            asset_fingerprints(&assets)[name]
+        )
+    }
+
+    #[test]
+    fn test_fingerprinted_asset_shares_encodings() {
+        let external_url_prefix = "http://example".to_string();
+        let mut posts = collect_posts(&external_url_prefix);
+        let post = posts
+            .iter_mut()
+            .find(|x| x.path == "20230706-binary-blog")
+            .unwrap();
+        post.assets.insert(
+            "notes.txt".to_string(),
+            Cow::Owned("some notes ".repeat(100).into_bytes()),
+        );
+        let fingerprinted = asset_fingerprints(&post.assets)["notes.txt"].clone();
+        let state = build_shared_state(posts, &external_url_prefix, &SiteOptions::default());
+        let plain = find_item(&state.root, "/20230706-binary-blog/notes.txt").unwrap();
+        let alias = find_item(
+            &state.root,
+            &format!("/20230706-binary-blog/{}", fingerprinted),
+        )
+        .unwrap();
+        assert!(!plain.encoded.is_empty());
+        for ((_, a), (_, b)) in plain.encoded.iter().zip(alias.encoded.iter()) {
+            assert_eq!(a.as_ptr(), b.as_ptr());
+        }
+    }
+
+    #[test]
+    fn test_asset_fingerprints_cover_image_variants() {
+        let mut assets: HashMap<String, Cow<'static, [u8]>> = HashMap::from([
+            ("fig.png".to_string(), Cow::Borrowed(b"png".as_slice())),
+            (
+                "fig.png.webp".to_string(),
+                Cow::Borrowed(b"webp".as_slice()),
+            ),
+        ]);
+        let before = asset_fingerprints(&assets);
+        assets.insert(
+            "fig.png.webp".to_string(),
+            Cow::Borrowed(b"re-encoded webp".as_slice()),
+        );
+        let after = asset_fingerprints(&assets);
+        assert_ne!(before["fig.png"], after["fig.png"]);
+        assert_ne!(before["fig.png.webp"], after["fig.png.webp"]);
+        assert_eq!(
+            after["fig.png.webp"],
+            fingerprint_asset_name("fig.png.webp", &[b"re-encoded webp"])
+        );
+    }
+
+    #[test_case("fig1.jpg", "fig1.e3b0c442.jpg" ; "extension")]
+    #[test_case("fig1.jpg.webp", "fig1.jpg.e3b0c442.webp" ; "double extension")]
+    #[test_case("LICENSE", "LICENSE.e3b0c442" ; "no extension")]
+    fn test_fingerprint_asset_name(name: &str, expected: &str) {
+        assert_eq!(fingerprint_asset_name(name, &[]), expected);
+    }
+
+    #[tokio::test]
+    async fn test_fingerprinted_asset_caching() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let url = fingerprinted_url("20230706-binary-blog", "pagespeed.png.webp");
+        let resp = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(url.strip_prefix("http://example").unwrap())
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(CACHE_CONTROL).unwrap(),
+            "public, max-age=31536000, immutable"
+        );
+
+        // the plain name still works for old links but only for a short time
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri("/20230706-binary-blog/pagespeed.png.webp")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "max-age=300");
+    }
+
+    #[test_case("feed=max-age=600, stale-while-revalidate=60", Ok((CacheClass::Feed, "max-age=600, stale-while-revalidate=60")) ; "valid")]
+    #[test_case(" Error = no-store ", Ok((CacheClass::Error, "no-store")) ; "trimmed")]
+    #[test_case("feeds=max-age=600", Err(()) ; "unknown class")]
+    #[test_case("post", Err(()) ; "missing directives")]
+    #[test_case("post=", Err(()) ; "empty directives")]
+    #[test_case("post=max-age=1\u{1}x", Err(()) ; "invalid header")]
+    fn test_parse_cache_policy_arg(arg: &str, expected: Result<(CacheClass, &str), ()>) {
+        assert_eq!(
+            parse_cache_policy_arg(arg).map_err(|_| ()),
+            expected.map(|(c, d)| (c, d.to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_policies() {
+        let mut options = SiteOptions::default();
+        options.cache_policies.insert(
+            CacheClass::Post,
+            CachePolicy {
+                cache_control: "max-age=60, stale-while-revalidate=600, stale-if-error=86400"
+                    .to_string(),
+                cdn_cache_control: Some("max-age=3600".to_string()),
+                cloudflare_cdn_cache_control: Some("max-age=86400".to_string()),
+            },
+        );
+        options
+            .cache_policies
+            .insert(CacheClass::Error, CachePolicy::new("no-store"));
+        options.cache_policies.insert(
+            CacheClass::Redirect,
+            CachePolicy::new("public, max-age=600, s-maxage=86400"),
+        );
+        let app = setup_router("http://example".to_string(), options);
+
+        let get = |uri: &'static str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let resp = get("/20230706-binary-blog/").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(CACHE_CONTROL).unwrap(),
+            "max-age=60, stale-while-revalidate=600, stale-if-error=86400"
+        );
+        assert_eq!(
+            resp.headers().get("cdn-cache-control").unwrap(),
+            "max-age=3600"
+        );
+        assert_eq!(
+            resp.headers().get("cloudflare-cdn-cache-control").unwrap(),
+            "max-age=86400"
+        );
+
+        let resp = get("/rss.xml").await.unwrap();
+        assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "max-age=300");
+        assert!(resp.headers().get("cdn-cache-control").is_none());
+
+        let resp = get("/nope").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "no-store");
+
+        let resp = get("/20230706-binary-blog").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            resp.headers().get(CACHE_CONTROL).unwrap(),
+            "public, max-age=600, s-maxage=86400"
+        );
+    }
+
+    #[test_case("/20230706-binary-blog/pagespeed.png", None, "image/png" ; "no accept")]
+    #[test_case("/20230706-binary-blog/pagespeed.png", Some("*/*"), "image/png" ; "wildcard")]
+    #[test_case("/20230706-binary-blog/pagespeed.png", Some("image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8"), "image/webp" ; "browser")]
+    #[test_case("/20230706-binary-blog/pagespeed.png", Some("image/webp;q=0.5, image/png"), "image/png" ; "original preferred")]
+    #[test_case("/20230706-binary-blog/pagespeed.png", Some("image/webp;q=0"), "image/png" ; "webp excluded")]
+    #[test_case("/url-image.jpg", Some("image/webp,*/*"), "image/webp" ; "root image")]
+    #[tokio::test]
+    async fn test_image_variants(uri: &str, accept: Option<&str>, content_type: &str) {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let mut req = Request::builder().uri(uri);
+        if let Some(accept) = accept {
+            req = req.header(ACCEPT, accept);
+        }
+        let resp = app
+            .clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), content_type);
+        assert_eq!(resp.headers().get(VARY).unwrap(), "Accept-Encoding, Accept");
+        let etag = resp.headers().get(ETAG).unwrap().clone();
+
+        // the etag belongs to the variant so revalidating with it only matches when the same variant is chosen
+        let mut req = Request::builder()
+            .uri(uri)
+            .header(IF_NONE_MATCH, etag.clone());
+        if let Some(accept) = accept {
+            req = req.header(ACCEPT, accept);
+        }
+        let resp = app
+            .clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        let other_accept = if content_type == "image/webp" {
+            "image/png"
+        } else {
+            "image/webp"
+        };
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header(ACCEPT, other_accept)
+                    .header(IF_NONE_MATCH, etag)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_image_without_variants() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri("/20230706-binary-blog/pagespeed.png.webp")
+                    .header(ACCEPT, "image/avif,image/webp")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), "image/webp");
+        assert_eq!(resp.headers().get(VARY).unwrap(), "Accept-Encoding");
+    }
+
+    #[test]
+    fn test_parse_redirects() {
+        let redirects = parse_redirects(
+            "# comment\n\n/a /b\n/c/* https://example.com/* 308\n  /d   410  \n/e /f 301\n",
+        )
+        .unwrap();
+        assert_eq!(
+            redirects,
+            vec![
+                Redirect {
+                    from: "/a".to_string(),
+                    to: Some("/b".to_string()),
+                    status: StatusCode::MOVED_PERMANENTLY,
+                },
+                Redirect {
+                    from: "/c/*".to_string(),
+                    to: Some("https://example.com/*".to_string()),
+                    status: StatusCode::PERMANENT_REDIRECT,
+                },
+                Redirect {
+                    from: "/d".to_string(),
+                    to: None,
+                    status: StatusCode::GONE,
+                },
+                Redirect {
+                    from: "/e".to_string(),
+                    to: Some("/f".to_string()),
+                    status: StatusCode::MOVED_PERMANENTLY,
+                },
+            ]
+        );
+    }
+
+    #[test_case("/a" ; "missing target")]
+    #[test_case("/a /b 302" ; "unsupported status")]
+    #[test_case("a /b" ; "relative path")]
+    #[test_case("/a /b/* 308" ; "wildcard target without wildcard path")]
+    fn test_parse_redirects_invalid(source: &str) {
+        assert!(parse_redirects(source).unwrap_err().starts_with("line 1: "));
+    }
+
+    #[test_case("/old", Some("/new/") ; "exact")]
+    #[test_case("/old/", Some("/new/") ; "trailing slash")]
+    #[test_case("/older", None ; "prefix of exact")]
+    #[test_case("/section/2013/06/", Some("/archive/2013/06/") ; "wildcard")]
+    #[test_case("/section/post", Some("/archive/post") ; "wildcard without slash")]
+    #[test_case("/section", Some("/archive/") ; "wildcard root")]
+    #[test_case("/sections", None ; "wildcard prefix")]
+    #[test_case("/moved/a/b", Some("/a/b") ; "wildcard to root")]
+    #[test_case("/moved//evil.com", None ; "empty leading segment")]
+    #[test_case("/moved/a//evil.com", None ; "empty inner segment")]
+    #[test_case("/moved/\\evil.com", None ; "backslash")]
+    #[test_case("/moved/../etc", None ; "parent")]
+    fn test_match_redirect(path: &str, expected: Option<&str>) {
+        let redirects =
+            parse_redirects("/old /new/\n/section/* /archive/*\n/moved/* /*\n").unwrap();
+        assert_eq!(
+            match_redirect(&redirects, path).and_then(|(_, l)| l),
+            expected.map(|e| e.to_string())
+        );
+    }
+
+    #[test]
+    fn test_validate_redirects() {
+        let posts = collect_posts(&"http://example".to_string());
+        let state = build_shared_state(
+            posts,
+            &"http://example".to_string(),
+            &SiteOptions::default(),
+        );
+        let redirects = parse_redirects(
+            "/a /20230706-binary-blog/\n/b /archive/2023/?x=1\n/c /nope/\n/d https://example.com/\n/e/* /f/*\n",
+        )
+        .unwrap();
+        assert_eq!(
+            validate_redirects(&state.root, &redirects),
+            vec!["/c redirects to /nope/ which does not exist".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redirects() {
+        let options = SiteOptions {
+            redirects: parse_redirects(
+                "/old-post /20230706-binary-blog/\n/old-archive/* /archive/* 308\n/removed 410\n/a/b/c/d /archive/\n",
+            )
+            .unwrap(),
+            ..SiteOptions::default()
+        };
+        let app = setup_router("http://example".to_string(), options);
+        let get = |uri: &'static str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header(ACCEPT, "text/html")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let resp = get("/old-post").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            resp.headers().get(LOCATION).unwrap(),
+            "/20230706-binary-blog/"
+        );
+
+        let resp = get("/old-archive/2023/?utm_source=x").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            resp.headers().get(LOCATION).unwrap(),
+            "/archive/2023/?utm_source=x"
+        );
+
+        let resp = get("/removed").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::GONE);
+        assert_eq!(
+            resp.headers().get(CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+
+        // deeper than any route so it only reaches the fallback
+        let resp = get("/a/b/c/d").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+
+        // existing content always wins
+        let resp = get("/archive/").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = get("/still-missing").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_redirect_to_other_host_refused() {
+        let external_url_prefix = "http://example".to_string();
+        let mut state = build_shared_state(
+            collect_posts(&external_url_prefix),
+            &external_url_prefix,
+            &SiteOptions::default(),
+        );
+        // startup validation rejects this target, so it can only come from a bug in the matching
+        state.redirects.push(Redirect {
+            from: "/a".to_string(),
+            to: Some("//evil.com/".to_string()),
+            status: StatusCode::MOVED_PERMANENTLY,
+        });
+        let app = crate::setup_router(Arc::new(state));
+        let resp = app
+            .oneshot(Request::builder().uri("/a").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(resp.headers().get(LOCATION).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_gone_page() {
+        let options = SiteOptions {
+            redirects: parse_redirects("/removed 410\n").unwrap(),
+            ..SiteOptions::default()
+        };
+        let app = setup_router("http://example".to_string(), options);
+        let get = |uri: &'static str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header(ACCEPT, "text/html")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let resp = get("/removed").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::GONE);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("<title>410 - Gone</title>"));
+        assert!(body.contains("<h1>Gone</h1>"));
+        assert!(!body.contains("Not found"));
+
+        let resp = get("/still-missing").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("<title>404 - Not Found</title>"));
+        assert!(body.contains("<h1>Not found</h1>"));
+    }
+
+    #[test_case("http://example", "/old-binary-blog", "/20230706-binary-blog/" ; "root")]
+    #[test_case("http://example", "/2023/binary-blog/", "/20230706-binary-blog/" ; "nested alias")]
+    #[test_case("http://example/blog", "/blog/old-binary-blog", "/blog/20230706-binary-blog/" ; "base path")]
+    #[tokio::test]
+    async fn test_post_aliases(external_url_prefix: &str, uri: &str, location: &str) {
+        let external_url_prefix = external_url_prefix.to_string();
+        let mut posts = collect_posts(&external_url_prefix);
+        let post = posts
+            .iter_mut()
+            .find(|x| x.path == "20230706-binary-blog")
+            .unwrap();
+        post.aliases = vec![
+            "/old-binary-blog".to_string(),
+            "2023/binary-blog/".to_string(),
+        ];
+        let app = crate::setup_router(Arc::new(build_shared_state(
+            posts,
+            &external_url_prefix,
+            &SiteOptions::default(),
+        )));
+
+        let resp = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(resp.headers().get(LOCATION).unwrap(), location);
+    }
+
+    #[test_case("/20230706-binary-blog/", "/20230706-binary-blog/" ; "already normal")]
+    #[test_case("/20230706-Binary-Blog/", "/20230706-Binary-Blog/" ; "case kept")]
+    #[test_case("/%7Efoo%2dbar", "/~foo-bar" ; "unreserved decoded")]
+    #[test_case("/%41", "/A" ; "decoded keeps case")]
+    #[test_case("/a%2fb%c3%a9", "/a%2Fb%C3%A9" ; "reserved uppercased")]
+    #[test_case("/100%", "/100%" ; "truncated")]
+    #[test_case("/%zz", "/%zz" ; "invalid")]
+    #[test_case("//evil.com/%41", "/evil.com/A" ; "leading slashes collapsed")]
+    #[test_case("/a//b///c/", "/a/b/c/" ; "inner slashes collapsed")]
+    fn test_normalize_path(path: &str, expected: &str) {
+        assert_eq!(normalize_path(path), expected);
+    }
+
+    #[test_case("/20230706-Binary-Blog/?x=1", "/20230706-binary-blog/?x=1" ; "uppercase")]
+    #[test_case("/20230706%2Dbinary-blog/", "/20230706-binary-blog/" ; "percent encoded")]
+    #[test_case("/20230706-binary-blog?utm_source=feed", "/20230706-binary-blog/?utm_source=feed" ; "slash with query")]
+    #[test_case("//evil.com/%41", "/evil.com/A" ; "protocol relative decoded")]
+    #[test_case("//evil.com/%7e", "/evil.com/~" ; "protocol relative unreserved")]
+    #[test_case("//20230706-binary-blog/", "/20230706-binary-blog/" ; "repeated slashes")]
+    #[tokio::test]
+    async fn test_canonical_path_redirects(uri: &str, location: &str) {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(resp.headers().get(LOCATION).unwrap(), location);
+        assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "max-age=300");
+    }
+
+    #[test_case("/a", true ; "path")]
+    #[test_case("//evil.com", false ; "protocol relative")]
+    #[test_case("/\\evil.com", false ; "backslash")]
+    #[test_case("https://evil.com", false ; "absolute")]
+    fn test_is_same_site_location(location: &str, expected: bool) {
+        assert_eq!(is_same_site_location(location), expected);
+    }
+
+    #[test_case("//evil.com/A" ; "protocol relative")]
+    #[test_case("/\\evil.com/A" ; "backslash")]
+    fn test_canonical_redirect_refuses_other_hosts(location: &str) {
+        let resp = gen_canonical_redirect(&test_state(), location.to_string(), None);
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(resp.headers().get(LOCATION).is_none());
+    }
+
+    #[test_case("/20230706-binary-blog/Missing.PNG" ; "missing asset")]
+    #[test_case("/Missing-Post/" ; "missing post")]
+    #[tokio::test]
+    async fn test_uppercase_miss_is_not_redirected(uri: &str) {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_uppercase_base_path() {
+        let app = setup_router("http://example/Blog".to_string(), SiteOptions::default());
+        let get = |uri: &'static str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+        assert_eq!(get("/Blog/").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(
+            get("/Blog/20230706-binary-blog/").await.unwrap().status(),
+            StatusCode::OK
+        );
+        let resp = get("/Blog/20230706-Binary-Blog/").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            resp.headers().get(LOCATION).unwrap(),
+            "/Blog/20230706-binary-blog/"
+        );
+    }
+
+    #[test_case("", &[] ; "root")]
+    #[test_case("/Blog", &[] ; "uppercase")]
+    #[test_case("/a%2fb", &["base path /a%2fb is not a normalised path"] ; "lowercase hex")]
+    #[test_case("/%7Eblog", &["base path /%7Eblog is not a normalised path"] ; "encoded unreserved")]
+    #[test_case("/a//b", &["base path /a//b is not a normalised path"] ; "empty segment")]
+    #[test_case("/a/../b", &["base path /a/../b is not a normalised path"] ; "dot segment")]
+    fn test_validate_base_path(base_path: &str, expected: &[&str]) {
+        let root = Item {
+            children: HashMap::new(),
+            ..test_item()
+        };
+        assert_eq!(validate_paths(base_path, &root), expected);
+    }
+
+    #[test]
+    fn test_validate_item_names() {
+        let post = Item {
+            children: HashMap::from([
+                ("Image.PNG".to_string(), Cow::Owned(test_item())),
+                ("100%41.png".to_string(), Cow::Owned(test_item())),
+            ]),
+            ..test_item()
+        };
+        let root = Item {
+            children: HashMap::from([("post".to_string(), Cow::Owned(post))]),
+            ..test_item()
+        };
+        assert_eq!(
+            validate_paths("", &root),
+            vec!["/post/100%41.png is not a normalised path"]
+        );
+    }
+
+    fn test_item() -> Item {
+        Item {
+            content: Cow::Borrowed(b""),
+            encoded: vec![],
+            content_type: HeaderValue::from_static("text/plain"),
+            etag: String::new(),
+            last_modified: None,
+            script_hashes: vec![],
+            image_variants: vec![],
+            cache_class: CacheClass::Asset,
+            children: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_canonical_host_redirect() {
+        let options = SiteOptions {
+            canonical_redirect_status: StatusCode::MOVED_PERMANENTLY,
+            canonical_host_redirect: true,
+            ..SiteOptions::default()
+        };
+        let app = setup_router("https://example.com".to_string(), options);
+        let get = |uri: &'static str, host: &'static str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header(HOST, host)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let resp = get("/20230706-Binary-Blog/?x=1", "www.example.com")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            resp.headers().get(LOCATION).unwrap(),
+            "https://example.com/20230706-binary-blog/?x=1"
+        );
+
+        // paths are only lowercased when that finds something
+        let resp = get("/About/?x=1", "www.example.com").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            resp.headers().get(LOCATION).unwrap(),
+            "https://example.com/About/?x=1"
+        );
+
+        let resp = get("/", "EXAMPLE.com").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = get("/readyz", "10.0.0.1:8080").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let resp = get("/20230706-binary-blog", "example.com").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            resp.headers().get(LOCATION).unwrap(),
+            "/20230706-binary-blog/"
+        );
+    }
+
+    #[test_case("308", Ok(308) ; "permanent")]
+    #[test_case("301", Ok(301) ; "moved")]
+    #[test_case("200", Err(()) ; "not a redirect")]
+    #[test_case("x", Err(()) ; "not a number")]
+    fn test_parse_redirect_status(arg: &str, expected: Result<u16, ()>) {
+        assert_eq!(parse_redirect_status(arg).map_err(|_| ()), expected);
+    }
+
+    #[tokio::test]
+    async fn test_index_brotli() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header(ACCEPT_ENCODING, "gzip, deflate, br, zstd")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(CONTENT_ENCODING).unwrap(), "br");
+        assert_eq!(resp.headers().get(VARY).unwrap(), "Accept-Encoding");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let mut decoded = vec![];
+        brotli::BrotliDecompress(&mut body.as_ref(), &mut decoded).unwrap();
+        assert!(String::from_utf8(decoded).unwrap().contains("<html"));
+    }
+
+    #[tokio::test]
+    async fn test_index_not_acceptable() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header(ACCEPT_ENCODING, "identity;q=0")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[tokio::test]
+    async fn test_livez() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri("/livez")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers().get(CONTENT_LENGTH).unwrap(), "0");
+        assert!(resp.headers().get(CONTENT_TYPE).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_readyz() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri("/readyz")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers().get(CONTENT_LENGTH).unwrap(), "0");
+        assert!(resp.headers().get(CONTENT_TYPE).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_readyz_draining() {
+        let external_url_prefix = "http://example".to_string();
+        let state = Arc::new(build_shared_state(
+            collect_posts(&external_url_prefix),
+            &external_url_prefix,
+            &SiteOptions::default(),
+        ));
+        let app = crate::setup_router(state.clone());
+        let get = |uri: &'static str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+        assert_eq!(
+            get("/readyz").await.unwrap().status(),
+            StatusCode::NO_CONTENT
+        );
+
+        state.draining.store(true, Ordering::Relaxed);
+        assert_eq!(
+            get("/readyz").await.unwrap().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        // requests keep being served while the load balancers catch up
+        assert_eq!(
+            get("/livez").await.unwrap().status(),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(get("/").await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_listeners_closed() {
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        let closed = tokio::spawn(listeners_closed(shutdown_rx));
+        shutdown_tx.send_replace(true);
+        drop(shutdown_tx);
+        tokio::time::timeout(Duration::from_secs(1), closed)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // losing the sender must not shut the listeners down
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        drop(shutdown_tx);
+        let closed = listeners_closed(shutdown_rx);
+        assert!(tokio::time::timeout(Duration::from_millis(50), closed)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_health_checks_verbose() {
+        let external_url_prefix = "http://example".to_string();
+        let state = Arc::new(build_shared_state(
+            collect_posts(&external_url_prefix),
+            &external_url_prefix,
+            &SiteOptions::default(),
+        ));
+        let app = crate::setup_router(state.clone());
+        let get = |uri: &'static str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+        let json = |resp: Response| async move {
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let resp = get("/readyz?verbose").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "no-store");
+        let body = json(resp).await;
+        assert_eq!(body["status"], "pass");
+        assert_eq!(body["checks"]["draining"], "pass");
+        assert_eq!(body["posts"], state.post_count);
+        assert!(body["posts"].as_u64().unwrap() > 0);
+        assert!(body["uptime_seconds"].is_u64());
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["pid"], std::process::id());
+        assert!(body["start_time"].is_string());
+
+        let body = json(get("/livez?verbose").await.unwrap()).await;
+        assert_eq!(body["status"], "pass");
+        assert_eq!(body["checks"]["watchdog"], "pass");
+
+        state.draining.store(true, Ordering::Relaxed);
+        let resp = get("/readyz?verbose").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = json(resp).await;
+        assert_eq!(body["status"], "fail");
+        assert_eq!(body["checks"]["draining"], "fail");
+    }
+
+    #[test]
+    fn test_footer_has_no_process_details() {
+        // the footer is in every page, so anything specific to the process would change the etags on each restart
+        let footer = pre_render_footer().into_string();
+        assert!(!footer.contains("pid="));
+        assert!(!footer.contains("start-time="));
+        assert!(footer.contains(&format!("version={}", env!("CARGO_PKG_VERSION"))));
+        // nor the clock, so the year comes from the newest post
+        let newest = collect_posts(&"http://example".to_string())
+            .iter()
+            .map(|x| x.date.year())
+            .max()
+            .unwrap();
+        assert!(footer.contains(&format!("© Ben Meier {}", newest)));
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let watchdog = Watchdog::default();
+        // not started yet, so there is nothing to judge
+        assert!(!watchdog.is_stalled(Duration::ZERO));
+        watchdog.beat();
+        assert!(!watchdog.is_stalled(Duration::from_secs(10)));
+        assert!(watchdog.is_stalled(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn test_metricz() {
+        let external_url_prefix = "http://example".to_string();
+        let state = Arc::new(build_shared_state(
+            collect_posts(&external_url_prefix),
+            &external_url_prefix,
+            &SiteOptions::default(),
+        ));
+        let app = crate::setup_router(state.clone());
+        let get = |uri: &'static str, headers: &[(HeaderName, &str)]| {
+            let mut req = Request::builder().uri(uri);
+            for (name, value) in headers {
+                req = req.header(name, *value);
+            }
+            app.clone().oneshot(req.body(Body::empty()).unwrap())
+        };
+
+        let resp = get("/", &[(ACCEPT_ENCODING, "br")]).await.unwrap();
+        let etag = resp
+            .headers()
+            .get(ETAG)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let brotli_length = resp.headers().get(CONTENT_LENGTH).unwrap().clone();
+        let resp = get("/", &[(ACCEPT_ENCODING, "br"), (IF_NONE_MATCH, &etag)])
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        // the body of a HEAD response is never sent, so it doesn't count towards the bytes
+        app.clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::HEAD)
+                    .uri("/")
+                    .header(ACCEPT_ENCODING, "br")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let mut identity_bytes = 0;
+        for resp in [
+            get("/", &[(IF_NONE_MATCH, "\"nope\"")]).await.unwrap(),
+            get("/missing/", &[]).await.unwrap(),
+            get("/wp-login.php", &[]).await.unwrap(),
+            get("/missing.png", &[]).await.unwrap(),
+            // rendered per request, without a Content-Length until the server adds one
+            get("/search?q=rust", &[]).await.unwrap(),
+        ] {
+            assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+            identity_bytes += resp.into_body().collect().await.unwrap().to_bytes().len();
+        }
+
+        let resp = get("/metricz", &[]).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(CONTENT_TYPE).unwrap(),
+            "text/plain; version=0.0.4; charset=utf-8"
+        );
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+        for line in [
+            "http_requests_total{route=\"/\",status=\"200\"} 3",
+            "http_requests_total{route=\"/search\",status=\"200\"} 1",
+            "http_requests_total{route=\"/\",status=\"304\"} 1",
+            "http_requests_total{route=\"/:a/\",status=\"404\"} 1",
+            "http_request_duration_seconds_bucket{route=\"/\",le=\"+Inf\"} 4",
+            "http_request_duration_seconds_count{route=\"/\"} 4",
+            &format!(
+                "http_response_bytes_total{{encoding=\"br\"}} {}",
+                brotli_length.to_str().unwrap()
+            ),
+            &format!(
+                "http_response_bytes_total{{encoding=\"identity\"}} {}",
+                identity_bytes
+            ),
+            "http_conditional_requests_total 2",
+            "http_not_modified_total 1",
+            "http_not_modified_ratio 0.5",
+            "http_not_found_total{class=\"file\"} 1",
+            "http_not_found_total{class=\"page\"} 1",
+            "http_not_found_total{class=\"probe\"} 1",
+            &format!("blog_posts {}", state.post_count),
+            "# TYPE process_open_fds gauge",
+        ] {
+            assert!(lines.contains(&line), "missing {}\n{}", line, body);
+        }
+        assert!(state.state_bytes > 0);
+        assert!(lines.contains(&format!("blog_state_bytes {}", state.state_bytes).as_str()));
+    }
+
+    #[test]
+    fn test_process_cpu_seconds() {
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+        let stat = "1234 (binary (blog) x) S 1 2 3 4 5 6 7 8 9 10 250 150 7 7 20 0 9 0 100 0 0";
+        assert_eq!(process_cpu_seconds(stat), Some(400.0 / ticks_per_second));
+        assert_eq!(process_cpu_seconds("1234 (truncated) S 1 2"), None);
+        let stat = std::fs::read_to_string("/proc/self/stat").unwrap();
+        assert!(process_cpu_seconds(&stat).is_some());
+    }
+
+    #[test_case("/missing/", "page"; "page")]
+    #[test_case("/missing", "page"; "page without slash")]
+    #[test_case("/20230706-binary-blog/missing.png", "file"; "file")]
+    #[test_case("/wp-admin/", "probe"; "wordpress")]
+    #[test_case("/index.PHP", "probe"; "php")]
+    #[test_case("/.env", "probe"; "dotfile")]
+    fn test_not_found_class(path: &str, expected: &str) {
+        assert_eq!(not_found_class(path), expected);
+    }
+
+    #[tokio::test]
+    async fn test_https_redirect_health_checks_share_state() {
+        let external_url_prefix = "http://example".to_string();
+        let state = Arc::new(build_shared_state(
+            collect_posts(&external_url_prefix),
+            &external_url_prefix,
+            &SiteOptions::default(),
+        ));
+        let get = |uri: &'static str| {
+            setup_https_redirect_router(443, state.clone())
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let resp = get("/livez?verbose").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(body["checks"]["watchdog"], "pass");
+
+        state.draining.store(true, Ordering::Relaxed);
+        let resp = get("/readyz").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_base_path() {
+        let app = setup_router("http://example/blog".to_string(), SiteOptions::default());
+        let get = |uri: &'static str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+        let body = |resp: Response| async move {
+            String::from_utf8(
+                resp.into_body()
+                    .collect()
+                    .await
+                    .unwrap()
+                    .to_bytes()
+                    .to_vec(),
+            )
+            .unwrap()
+        };
+
+        let resp = get("/blog/").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let index = body(resp).await;
+        assert!(index.contains("href=\"/blog/feed.xml\""));
+        assert!(index.contains("href=\"/blog/20230706-binary-blog/\""));
+        assert!(index.contains("action=\"/blog/search\""));
+        assert!(!index.contains("href=\"/20230706-binary-blog/\""));
+
+        let resp = get("/blog/20230706-binary-blog").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            resp.headers().get(LOCATION).unwrap(),
+            "/blog/20230706-binary-blog/"
+        );
+
+        let resp = get("/blog/20230706-binary-blog/").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(body(resp).await.contains(
+            "<link rel=\"canonical\" href=\"http://example/blog/20230706-binary-blog/\">"
+        ));
+
+        let resp = get("/blog/sitemap.xml").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let sitemap = body(resp).await;
+        assert!(sitemap.contains("<loc>http://example/blog/20230706-binary-blog/</loc>"));
+        assert!(!sitemap.contains("http://example/blog/blog/"));
+
+        let resp = get("/blog/feed.xml").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let feed = body(resp).await;
+        assert!(feed.contains("http://example/blog/20230706-binary-blog/"));
+        assert!(!feed.contains("http://example/blog/blog/"));
+
+        let resp = get("/blog?x=1").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(resp.headers().get(LOCATION).unwrap(), "/blog/?x=1");
+
+        let resp = get("/blog/robots.txt").await.unwrap();
+        assert!(body(resp).await.contains("Disallow: /blog/search\n"));
+        // crawlers only look at the root of the host
+        let resp = get("/robots.txt").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let robots = body(resp).await;
+        assert!(robots.contains("Disallow: /blog/search\n"));
+        assert!(robots.contains("Sitemap: http://example/blog/sitemap.xml\n"));
+
+        let resp = get("/20230706-binary-blog/").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = get("/blog/missing/").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = get("/livez").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = get("/blog/livez").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test_case("", ""; "empty")]
+    #[test_case("http://example", ""; "no path")]
+    #[test_case("http://example/", ""; "root")]
+    #[test_case("https://example.com/blog", "/blog"; "path")]
+    #[test_case("https://example.com/a/b/", "/a/b"; "trailing slash")]
+    fn test_url_base_path(prefix: &str, expected: &str) {
+        assert_eq!(url_base_path(prefix), expected);
+    }
+
+    #[tokio::test]
+    async fn test_strict_transport_security() {
+        let app = setup_router("https://example.com".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(resp.headers().get(STRICT_TRANSPORT_SECURITY).is_none());
+
+        let options = SiteOptions {
+            strict_transport_security: Some("max-age=31536000".to_string()),
+            ..SiteOptions::default()
+        };
+        let app = setup_router("https://example.com".to_string(), options);
+        for uri in ["/", "/missing/", "/20230706-binary-blog", "/livez"] {
+            let resp = app
+                .clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                resp.headers().get(STRICT_TRANSPORT_SECURITY).unwrap(),
+                "max-age=31536000",
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[test_case(443, "example.com", "/", "https://example.com/"; "default port")]
+    #[test_case(443, "example.com:80", "/a/?b=c", "https://example.com/a/?b=c"; "drops plaintext port")]
+    #[test_case(8443, "example.com:8080", "/a", "https://example.com:8443/a"; "custom port")]
+    #[test_case(443, "[::1]:80", "/", "https://[::1]/"; "ipv6 with port")]
+    #[test_case(8443, "[::1]", "/", "https://[::1]:8443/"; "ipv6")]
+    #[tokio::test]
+    async fn test_https_redirect(port: u16, host: &str, uri: &str, location: &str) {
+        let resp = setup_https_redirect_router(port, test_state())
+            .oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header(HOST, host)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(resp.headers().get(LOCATION).unwrap(), location);
+    }
+
+    #[tokio::test]
+    async fn test_https_redirect_health_checks() {
+        for uri in ["/livez", "/readyz"] {
+            let resp = setup_https_redirect_router(443, test_state())
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_robots() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri("/robots.txt")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(CONTENT_LENGTH).unwrap(), "131");
+        assert_eq!(
+            resp.headers().get(CONTENT_TYPE).unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        assert!(resp.headers().get(ETAG).is_some());
+        assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "max-age=300");
+    }
+
+    #[tokio::test]
+    async fn test_rss() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri("/rss.xml")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), "text/xml");
+        assert!(resp.headers().get(ETAG).is_some());
+        assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "max-age=300");
+    }
+
+    #[tokio::test]
+    async fn test_feed() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri("/feed.xml")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), "text/xml");
+        assert!(resp.headers().get(ETAG).is_some());
+        assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "max-age=300");
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use axum::body::Body;
-    use axum::http::header::{ACCEPT, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, ETAG};
-    use axum::http::{HeaderValue, Method, Request, StatusCode};
-    use http_body_util::BodyExt;
-    use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING, LOCATION};
-    // for `oneshot` and `ready`
-    use test_case::test_case;
-    use tower::ServiceExt;
-
-    use crate::{setup_router, Asset, CONTENT_FILE_NAME};
 
     #[tokio::test]
-    async fn test_index() {
-        let app = setup_router("http://example".to_string());
+    async fn test_atom() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
         let resp = app
-            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .oneshot(
+                Request::builder()
+                    .uri("/atom.xml")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
             .await
             .unwrap();
-
         assert_eq!(resp.status(), StatusCode::OK);
         assert_eq!(
             resp.headers().get(CONTENT_TYPE).unwrap(),
-            "text/html; charset=utf-8"
+            "application/atom+xml"
         );
-        let length: u32 = resp
-            .headers()
-            .get(CONTENT_LENGTH)
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .parse()
-            .unwrap();
-        assert!(length > 1);
         assert!(resp.headers().get(ETAG).is_some());
-        assert!(resp.headers().get(CONTENT_ENCODING).is_none());
-        assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "max-age=300");
+        let bod = resp.into_body().collect().await.unwrap().to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        assert!(body_str.contains("<content type=\"html\">"));
+        assert!(body_str.contains(&format!(
+            "src=&quot;{}&quot;",
+            fingerprinted_url("20230706-binary-blog", "blog-infra.drawio.png.webp")
+        )));
     }
 
     #[tokio::test]
-    async fn test_redirect_slash() {
-        let app = setup_router("http://example".to_string());
+    async fn test_sitemap() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
         let resp = app
             .oneshot(
                 Request::builder()
-                    .uri("/20230706-binary-blog")
+                    .uri("/sitemap.xml")
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), "text/xml");
+        let bod = resp.into_body().collect().await.unwrap().to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        assert!(body_str.contains("<loc>http://example/</loc>"));
+        assert!(body_str.contains(
+            "<url><loc>http://example/20230706-binary-blog/</loc><lastmod>2023-07-06T00:00:00Z</lastmod>"
+        ));
+        assert!(body_str.contains(&format!(
+            "<image:loc>{}</image:loc>",
+            fingerprinted_url("20230706-binary-blog", "pagespeed.png.webp")
+        )));
+        assert!(!body_str.contains("pagespeed.png.webp</image:loc>"));
+        assert!(!body_str.contains("robots.txt"));
+        assert!(body_str.contains("<loc>http://example/archive/2023/07/</loc>"));
+    }
 
-        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
-        assert_eq!(
-            resp.headers().get(LOCATION).unwrap(),
-            "/20230706-binary-blog/"
+    #[tokio::test]
+    async fn test_rss_full_content() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(
+                Request::builder()
+                    .uri("/rss.xml")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let bod = resp.into_body().collect().await.unwrap().to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        assert!(body_str.contains("<content:encoded>"));
+        assert!(
+            body_str.contains("href=&quot;http://example/20230705-home-lab-infrastructure&quot;")
         );
-        let length: u32 = resp
+        assert!(!body_str.contains("src=&quot;./"));
+    }
+
+    #[test_case("/20230706-binary-blog/", &fingerprinted_url("20230706-binary-blog", "blog-infra.drawio.png.webp"), "summary_large_image"; "first image")]
+    #[test_case("/20180429-faultz/", "http://example/20180429-faultz/og.png", "summary_large_image"; "social card")]
+    #[tokio::test]
+    async fn test_post_social_metadata(uri: &str, image: &str, card: &str) {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bod = resp.into_body().collect().await.unwrap().to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        assert!(body_str.contains(&format!(
+            "<link rel=\"canonical\" href=\"http://example{}\">",
+            uri
+        )));
+        assert!(body_str.contains(&format!(
+            "<meta property=\"og:image\" content=\"{}\">",
+            image
+        )));
+        assert!(body_str.contains(&format!(
+            "<meta name=\"twitter:card\" content=\"{}\">",
+            card
+        )));
+        assert!(body_str.contains("<meta property=\"og:description\" content=\""));
+    }
+
+    #[test_case("/", "Blog"; "index")]
+    #[test_case("/20230706-binary-blog/", "BlogPosting"; "post")]
+    #[tokio::test]
+    async fn test_json_ld(uri: &str, kind: &str) {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let csp = resp
             .headers()
-            .get(CONTENT_LENGTH)
+            .get(CONTENT_SECURITY_POLICY)
             .unwrap()
             .to_str()
             .unwrap()
-            .parse()
+            .to_string();
+        let bod = resp.into_body().collect().await.unwrap().to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        let script = body_str
+            .split("<script type=\"application/ld+json\">")
+            .nth(1)
+            .unwrap()
+            .split("</script>")
+            .next()
             .unwrap();
-        assert_eq!(length, 0);
-        assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "max-age=300");
+        assert!(csp.contains(&format!("script-src {}", make_csp_hash(script))));
+        let value: serde_json::Value = serde_json::from_str(script).unwrap();
+        assert_eq!(value["@type"], kind);
+        assert_eq!(value["author"]["name"], "Ben Meier");
+        if kind == "BlogPosting" {
+            assert!(value["wordCount"].as_u64().unwrap() > 100);
+            assert!(body_str.contains("class=\"container h-entry\""));
+            assert!(body_str.contains("class=\"e-content\""));
+        }
     }
 
     #[tokio::test]
-    async fn test_index_gzipped() {
-        let app = setup_router("http://example".to_string());
+    async fn test_search() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
         let resp = app
             .oneshot(
                 Request::builder()
-                    .uri("/")
-                    .header(
-                        ACCEPT_ENCODING,
-                        HeaderValue::from_str("deflate,gzip").unwrap(),
-                    )
+                    .uri("/search?q=Honeycomb+OpenTelemetry")
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        let bod = resp.into_body().collect().await.unwrap().to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        let first_result = body_str.split("<li>").nth(1).unwrap();
+        assert!(first_result.contains("href=\"/20240331-prom-to-honeycomb/\""));
+        assert!(first_result.contains("<mark>Honeycomb</mark>"));
+        assert!(body_str.contains("value=\"Honeycomb OpenTelemetry\""));
+    }
 
+    #[test_case("/search"; "empty")]
+    #[test_case("/search?q=%3Cxyzzy%3E"; "escaped")]
+    #[test_case("/search?q=xyzzyplugh"; "no results")]
+    #[tokio::test]
+    async fn test_search_no_results(uri: &str) {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
         assert_eq!(resp.status(), StatusCode::OK);
-        assert_eq!(resp.headers().get(CONTENT_ENCODING).unwrap(), "deflate");
+        let bod = resp.into_body().collect().await.unwrap().to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        assert!(!body_str.contains("<li>"));
+        assert!(!body_str.contains("<xyzzy>"));
     }
 
     #[tokio::test]
-    async fn test_livez() {
-        let app = setup_router("http://example".to_string());
+    async fn test_related_posts() {
+        let mut previous: Option<Vec<String>> = None;
+        for _ in 0..2 {
+            let app = setup_router("http://example".to_string(), SiteOptions::default());
+            let resp = app
+                .oneshot(
+                    Request::builder()
+                        .uri("/20230706-binary-blog/")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let bod = resp.into_body().collect().await.unwrap().to_bytes();
+            let body_str = String::from_utf8_lossy(bod.as_ref());
+            let section = body_str
+                .split("<section class=\"related-posts\">")
+                .nth(1)
+                .unwrap()
+                .split("</section>")
+                .next()
+                .unwrap();
+            let links: Vec<String> = section
+                .split("href=\"")
+                .skip(1)
+                .map(|x| x.split('"').next().unwrap().to_string())
+                .collect();
+            assert_eq!(links.len(), 3);
+            assert!(!links.contains(&"/20230706-binary-blog/".to_string()));
+            assert!(links.contains(&"/20230705-home-lab-infrastructure/".to_string()));
+            if let Some(p) = previous {
+                assert_eq!(p, links);
+            }
+            previous = Some(links);
+        }
+    }
+
+    #[test]
+    fn test_related_posts_override() {
+        let mut posts = collect_posts(&"http://example".to_string());
+        let index = posts
+            .iter()
+            .position(|x| x.path == "20230706-binary-blog")
+            .unwrap();
+        let computed = posts[index].related.clone();
+        // pick posts the similarity scores didn't, listed in the opposite order to the posts
+        let mut declared: Vec<String> = posts
+            .iter()
+            .map(|x| x.path.clone())
+            .filter(|x| *x != posts[index].path && !computed.contains(x))
+            .take(2)
+            .collect();
+        declared.reverse();
+
+        posts[index].related = declared.clone();
+        posts[index].related.insert(1, "no-such-post".to_string());
+        compute_related_posts(&mut posts);
+        assert_eq!(posts[index].related, declared);
+    }
+
+    #[test_case("/20230705-home-lab-infrastructure/", &["/20231104-data-storage-on-hensteeth/", "/20230706-binary-blog/"]; "relative links")]
+    #[test_case("/20230706-binary-blog/", &["/20240331-prom-to-honeycomb/"]; "absolute link")]
+    #[tokio::test]
+    async fn test_backlinks(uri: &str, expected: &[&str]) {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
         let resp = app
-            .oneshot(
-                Request::builder()
-                    .uri("/livez")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
             .await
             .unwrap();
-        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
-        assert_eq!(resp.headers().get(CONTENT_LENGTH).unwrap(), "0");
-        assert!(resp.headers().get(CONTENT_TYPE).is_none());
+        let bod = resp.into_body().collect().await.unwrap().to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        let section = body_str
+            .split("<section class=\"backlinks\">")
+            .nth(1)
+            .unwrap()
+            .split("</section>")
+            .next()
+            .unwrap();
+        let links: Vec<&str> = section
+            .split("href=\"")
+            .skip(1)
+            .map(|x| x.split('"').next().unwrap())
+            .collect();
+        assert_eq!(links, expected);
+    }
+
+    #[test]
+    fn test_internal_links_are_valid() {
+        let posts = collect_posts(&"http://example".to_string());
+        for x in posts.iter() {
+            for l in x.links.iter() {
+                assert!(
+                    posts.iter().any(|y| y.path.eq(l)),
+                    "post {} links to unknown post {}",
+                    x.path,
+                    l
+                );
+            }
+        }
     }
 
+    #[test_case("/archive/", &["/archive/2023/", "/archive/2013/"]; "archive")]
+    #[test_case("/archive/2023/", &["/archive/2023/11/", "/20231104-data-storage-on-hensteeth/", "/archive/2023/07/", "/20230706-binary-blog/"]; "year")]
+    #[test_case("/archive/2023/07/", &["/20230706-binary-blog/", "/20230705-home-lab-infrastructure/"]; "month")]
     #[tokio::test]
-    async fn test_readyz() {
-        let app = setup_router("http://example".to_string());
+    async fn test_archive(uri: &str, expected: &[&str]) {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(ETAG).is_some());
+        let bod = resp.into_body().collect().await.unwrap().to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        let main = body_str.split("<main").nth(1).unwrap();
+        for e in expected {
+            assert!(main.contains(&format!("href=\"{}\"", e)), "missing {}", e);
+        }
+    }
+
+    #[test_case("/archive/2023", "/archive/2023/"; "year")]
+    #[test_case("/archive/2023/07", "/archive/2023/07/"; "month")]
+    #[tokio::test]
+    async fn test_archive_redirect_slash(uri: &str, location: &str) {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(resp.headers().get(LOCATION).unwrap(), location);
+    }
+
+    #[tokio::test]
+    async fn test_index_year_groups() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let bod = resp.into_body().collect().await.unwrap().to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        assert!(body_str
+            .contains("<h2><a href=\"/archive/2024/\">2024</a></h2><ul class=\"index-nav-ul\">"));
+        assert!(!body_str.contains("<ul class=\"index-nav-ul\"></ul>"));
+        assert_eq!(
+            body_str.matches("<ul").count(),
+            body_str.matches("</ul>").count()
+        );
+    }
+
+    #[test_case("/", 0, Some("/page/2/"), None; "first page")]
+    #[test_case("/page/2/", 10, None, Some("/"); "last page")]
+    #[tokio::test]
+    async fn test_index_pagination(uri: &str, skip: usize, next: Option<&str>, prev: Option<&str>) {
+        let post_count = Asset::iter()
+            .filter(|p| p.ends_with(CONTENT_FILE_NAME))
+            .count();
+        let entries = (post_count - skip).min(10);
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bod = resp.into_body().collect().await.unwrap().to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        assert_eq!(body_str.matches("<li class=\"h-entry\">").count(), entries);
+        assert_eq!(
+            body_str.contains(&format!(
+                "<link rel=\"next\" href=\"{}\">",
+                next.unwrap_or("")
+            )),
+            next.is_some()
+        );
+        assert_eq!(
+            body_str.contains(&format!(
+                "<link rel=\"prev\" href=\"{}\">",
+                prev.unwrap_or("")
+            )),
+            prev.is_some()
+        );
+        assert!(body_str.contains(&format!(
+            "<link rel=\"canonical\" href=\"http://example{}\">",
+            uri
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_index_featured_posts() {
+        let external_url_prefix = "http://example".to_string();
+        let mut posts = collect_posts(&external_url_prefix);
+        let post_count = posts.len();
+        // the oldest post would otherwise be on the last page
+        posts
+            .iter_mut()
+            .find(|x| x.path == "20130607-programmatic-pdf")
+            .unwrap()
+            .featured = true;
+        let app = crate::setup_router(Arc::new(build_shared_state(
+            posts,
+            &external_url_prefix,
+            &SiteOptions::default(),
+        )));
+        let get = |uri: &'static str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let bod = get("/")
+            .await
+            .unwrap()
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        let featured = body_str
+            .split("<nav class=\"h-feed featured\">")
+            .nth(1)
+            .unwrap()
+            .split("</nav>")
+            .next()
+            .unwrap();
+        assert!(featured.contains("href=\"/20130607-programmatic-pdf/\""));
+        assert_eq!(
+            body_str
+                .matches("href=\"/20130607-programmatic-pdf/\"")
+                .count(),
+            1
+        );
+        assert_eq!(body_str.matches("<li class=\"h-entry\">").count(), 1 + 10);
+
+        let bod = get("/page/2/")
+            .await
+            .unwrap()
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        assert!(!body_str.contains("href=\"/20130607-programmatic-pdf/\""));
+        assert_eq!(
+            body_str.matches("<li class=\"h-entry\">").count(),
+            post_count - 1 - 10
+        );
+    }
+
+    #[test_case("http://example", "/page/1/", "/" ; "root")]
+    #[test_case("http://example", "/page/1", "/" ; "no slash")]
+    #[test_case("http://example/blog", "/blog/page/1/", "/blog/" ; "base path")]
+    #[tokio::test]
+    async fn test_first_index_page_redirects(external_url_prefix: &str, uri: &str, location: &str) {
+        let app = setup_router(external_url_prefix.to_string(), SiteOptions::default());
+        let resp = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(resp.headers().get(LOCATION).unwrap(), location);
+    }
+
+    #[tokio::test]
+    async fn test_index_unpaginated_with_summaries() {
+        let app = setup_router(
+            "http://example".to_string(),
+            SiteOptions {
+                index_page_size: 0,
+                index_summaries: true,
+                ..SiteOptions::default()
+            },
+        );
+        let resp = app
+            .clone()
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let bod = resp.into_body().collect().await.unwrap().to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        let post_count = Asset::iter()
+            .filter(|p| p.ends_with(CONTENT_FILE_NAME))
+            .count();
+        assert_eq!(
+            body_str.matches("<li class=\"h-entry\">").count(),
+            post_count
+        );
+        assert_eq!(body_str.matches("class=\"p-summary\"").count(), post_count);
+        assert!(!body_str.contains("rel=\"next\""));
+
         let resp = app
             .oneshot(
                 Request::builder()
-                    .uri("/readyz")
+                    .uri("/page/2/")
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
-        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
-        assert_eq!(resp.headers().get(CONTENT_LENGTH).unwrap(), "0");
-        assert!(resp.headers().get(CONTENT_TYPE).is_none());
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
     }
 
     #[tokio::test]
-    async fn test_robots() {
-        let app = setup_router("http://example".to_string());
+    async fn test_opensearch() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
         let resp = app
             .oneshot(
                 Request::builder()
-                    .uri("/robots.txt")
+                    .uri("/opensearch.xml")
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
         assert_eq!(resp.status(), StatusCode::OK);
-        assert_eq!(resp.headers().get(CONTENT_LENGTH).unwrap(), "77");
         assert_eq!(
             resp.headers().get(CONTENT_TYPE).unwrap(),
-            "text/plain; charset=utf-8"
+            "application/opensearchdescription+xml"
+        );
+        let bod = resp.into_body().collect().await.unwrap().to_bytes();
+        let body_str = String::from_utf8_lossy(bod.as_ref());
+        assert!(body_str.contains("template=\"http://example/search?q={searchTerms}\""));
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("Hello, wörld! A x2 go-lang"),
+            vec!["hello", "wörld", "x2", "go", "lang"]
+        );
+    }
+
+    #[test]
+    fn test_search_snippet() {
+        let snippet = search_snippet("the quick brown fox jumps", "fox");
+        assert_eq!(
+            snippet,
+            vec![
+                ("the quick brown ".to_string(), false),
+                ("fox".to_string(), true),
+                (" jumps".to_string(), false),
+            ]
         );
-        assert!(resp.headers().get(ETAG).is_some());
-        assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "max-age=300");
     }
 
     #[tokio::test]
-    async fn test_rss() {
-        let app = setup_router("http://example".to_string());
+    async fn test_social_card() {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
         let resp = app
             .oneshot(
                 Request::builder()
-                    .uri("/rss.xml")
+                    .uri("/20180429-faultz/og.png")
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
         assert_eq!(resp.status(), StatusCode::OK);
-        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), "text/xml");
+        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), "image/png");
         assert!(resp.headers().get(ETAG).is_some());
         assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "max-age=300");
+        let bod = resp.into_body().collect().await.unwrap().to_bytes();
+        let reader = png::Decoder::new(bod.as_ref()).read_info().unwrap();
+        assert_eq!(reader.info().width, 1200);
+        assert_eq!(reader.info().height, 630);
+    }
+
+    #[test_case("short text", 20, "short text"; "short")]
+    #[test_case("some  longer\ntext here", 14, "some longer…"; "truncated")]
+    #[test_case("some longer text here", 15, "some longer…"; "ellipsis counted")]
+    #[test_case("supercalifragilistic text", 10, "superca…"; "long first word")]
+    #[test_case("ééééé text", 6, "é…"; "long first word multibyte")]
+    fn test_truncate_words(text: &str, max_length: usize, expected: &str) {
+        let truncated = truncate_words(text, max_length);
+        assert_eq!(truncated, expected);
+        assert!(truncated.len() <= max_length);
+    }
+
+    #[test]
+    fn test_post_declared_metadata() {
+        let external_url_prefix = "http://example".to_string();
+        let posts = collect_posts(&external_url_prefix);
+        let post = posts
+            .iter()
+            .find(|x| x.path == "20230706-binary-blog")
+            .unwrap();
+        let body =
+            String::from_utf8(pre_render_post(post, &[], &external_url_prefix).to_vec()).unwrap();
+        assert!(
+            body.contains("<link rel=\"canonical\" href=\"http://example/20230706-binary-blog/\">")
+        );
+        assert!(!body.contains("article:tag"));
+
+        let source = concat!(
+            "<meta x-title=\"Declared\"/>\n",
+            "<meta x-canonical=\"https://elsewhere/binary-blog\"/>\n",
+            "<meta x-image=\"/20230706-binary-blog/gtmetrix.png\"/>\n",
+            "<meta x-tags=\"rust, self-hosting\"/>\n",
+            "<meta x-related=\"20230705-home-lab-infrastructure\"/>\n",
+            "<meta x-aliases=\"/old-post, /older-post\"/>\n",
+            "<meta x-featured=\"true\"/>\n",
+            "\nSome text.\n",
+        );
+        let parsed = parse_post(
+            "20230706-binary-blog".to_string(),
+            source,
+            HashMap::new(),
+            &external_url_prefix,
+        );
+        assert_eq!(parsed.title, "Declared");
+        assert_eq!(parsed.related, vec!["20230705-home-lab-infrastructure"]);
+        assert_eq!(parsed.aliases, vec!["/old-post", "/older-post"]);
+        assert!(parsed.featured);
+        assert_eq!(parsed.description, "Some text.");
+        let post = &parsed;
+        let body =
+            String::from_utf8(pre_render_post(post, &[], &external_url_prefix).to_vec()).unwrap();
+        assert!(body.contains("<link rel=\"canonical\" href=\"https://elsewhere/binary-blog\">"));
+        assert!(
+            body.contains("<meta property=\"og:url\" content=\"https://elsewhere/binary-blog\">")
+        );
+        assert!(body.contains(
+            "<meta property=\"og:image\" content=\"http://example/20230706-binary-blog/gtmetrix.png\">"
+        ));
+        // the size is only known for the generated card
+        assert!(!body.contains("og:image:width"));
+        assert!(body.contains("<meta property=\"article:tag\" content=\"rust\"><meta property=\"article:tag\" content=\"self-hosting\">"));
+    }
+
+    #[test_case("image.png", "http://example/post/image.png"; "relative")]
+    #[test_case("./image.png", "http://example/post/image.png"; "dot relative")]
+    #[test_case("../other/", "http://example/other/"; "parent")]
+    #[test_case("/other/", "http://example/other/"; "absolute path")]
+    #[test_case("#code", "http://example/post/#code"; "fragment")]
+    #[test_case("x.png?a=b#c", "http://example/post/x.png?a=b#c"; "query")]
+    #[test_case("https://other/x", "https://other/x"; "external")]
+    #[test_case("mailto:a@b", "mailto:a@b"; "mailto")]
+    #[test_case("tel:+123", "tel:+123"; "tel")]
+    #[test_case("ftp://host/x", "ftp://host/x"; "ftp")]
+    #[test_case("//cdn.example/x.png", "//cdn.example/x.png"; "protocol relative")]
+    #[test_case("dir/a:b.png", "http://example/post/dir/a:b.png"; "colon in path")]
+    fn test_absolutize_url(url: &str, expected: &str) {
+        assert_eq!(absolutize_url(url, "http://example", "post"), expected);
     }
 
+    #[test_case("/20230706-binary-blog/pagespeed.png", StatusCode::OK ; "asset")]
+    #[test_case("/20230706-binary-blog/pagespeed.png/", StatusCode::NOT_FOUND ; "asset with slash")]
+    #[test_case("/feed.xml/", StatusCode::NOT_FOUND ; "feed with slash")]
+    #[test_case("/20230706-binary-blog/", StatusCode::OK ; "post")]
     #[tokio::test]
-    async fn test_feed() {
-        let app = setup_router("http://example".to_string());
+    async fn test_only_pages_have_slash_paths(uri: &str, status: StatusCode) {
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
         let resp = app
-            .oneshot(
-                Request::builder()
-                    .uri("/feed.xml")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
             .await
             .unwrap();
-        assert_eq!(resp.status(), StatusCode::OK);
-        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), "text/xml");
-        assert!(resp.headers().get(ETAG).is_some());
-        assert_eq!(resp.headers().get(CACHE_CONTROL).unwrap(), "max-age=300");
+        assert_eq!(resp.status(), status);
     }
 
     #[test_case("/a"; "plain/a")]
@@ -1150,7 +6851,7 @@ mod tests {
     #[test_case("/a/b/c"; "plain/a/b/c")]
     #[tokio::test]
     async fn test_plain_404(uri: &str) {
-        let app = setup_router("http://example".to_string());
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
         let resp = app
             .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
             .await
@@ -1167,7 +6868,7 @@ mod tests {
     #[test_case("/a/b/c"; "html/a/b/c")]
     #[tokio::test]
     async fn test_html_404(uri: &str) {
-        let app = setup_router("http://example".to_string());
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
         let mut req = Request::builder().uri(uri);
         req.headers_mut()
             .unwrap()
@@ -1193,11 +6894,12 @@ mod tests {
     #[test_case("/a", 405; "post/a")]
     #[test_case("/a/", 405; "post/a/")]
     #[test_case("/a/b", 405; "post/a/b")]
-    #[test_case("/a/b/", 404; "post/a/b/")]
-    #[test_case("/a/b/c", 404; "post/a/b/c")]
+    #[test_case("/a/b/", 405; "post/a/b/")]
+    #[test_case("/a/b/c", 405; "post/a/b/c")]
+    #[test_case("/a/b/c/d", 404; "post/a/b/c/d")]
     #[tokio::test]
     async fn test_post(uri: &str, code: u16) {
-        let app = setup_router("http://example".to_string());
+        let app = setup_router("http://example".to_string(), SiteOptions::default());
         let resp = app
             .oneshot(
                 Request::builder()
@@ -1230,7 +6932,7 @@ mod tests {
 
         for x in blogs {
             println!("checking {}", x);
-            let app = setup_router("http://example".to_string());
+            let app = setup_router("http://example".to_string(), SiteOptions::default());
             let resp = app
                 .oneshot(
                     Request::builder()
@@ -1254,7 +6956,7 @@ mod tests {
 
             for y in links {
                 println!("checking {}", y);
-                let app2 = setup_router("http://example".to_string());
+                let app2 = setup_router("http://example".to_string(), SiteOptions::default());
                 let resp2 = app2
                     .oneshot(
                         Request::builder()
@@ -1266,7 +6968,10 @@ mod tests {
                     .unwrap();
                 assert_eq!(resp2.status(), StatusCode::OK);
                 assert!(resp2.headers().get(ETAG).is_some());
-                assert_eq!(resp2.headers().get(CACHE_CONTROL).unwrap(), "max-age=300");
+                assert_eq!(
+                    resp2.headers().get(CACHE_CONTROL).unwrap(),
+                    "public, max-age=31536000, immutable"
+                );
             }
         }
     }